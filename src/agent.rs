@@ -0,0 +1,602 @@
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestMessage, ChatCompletionTool,
+    ChatCompletionToolType, CreateChatCompletionRequest, FunctionObject,
+};
+use std::time::Duration;
+
+use crate::completion::{self, Endpoint, Endpoints};
+use crate::confirm::{self, Decision};
+use crate::output::{self, Event};
+use crate::policy::{Policy, Verdict};
+use crate::sandbox::{Backend, Sandbox};
+use crate::session::{self, State};
+use crate::shell::Shell;
+use crate::tokens::{self, TokenCounter};
+use crate::{Args, Interrupt, ToolMode, extract, log, log_event, message, prompt, summary};
+
+/// Why [`Agent::send`] returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurnEnd {
+    /// The model answered, and said it's fully done when looping.
+    Finished,
+    /// The model answered without running anything while looping.
+    WaitingForUser,
+    /// Interrupted or canceled by the user.
+    Canceled,
+    /// Reached `--max-iterations` while looping.
+    OutOfIterations,
+}
+
+/// Stores what a continued session has to run with again: where its scripts run and how the
+/// model is asked.
+fn record_settings(state: &mut State, args: &Args) {
+    state.model = args.model.clone();
+    state.sampling = args.sampling.clone();
+    state.target = args.target.clone();
+    state.hosts = args.hosts.clone();
+    state.sandbox = Some(args.sandbox);
+    state.sandbox_image = (args.sandbox == Sandbox::Container).then(|| args.sandbox_image.clone());
+}
+
+/// Told to the model about a script skipped by `--stop-on-error`.
+const SKIPPED_AFTER_FAILURE: &str = "Not run because an earlier script failed.";
+
+/// What the model is told about a script, and whether it ran successfully.
+struct Outcome {
+    message: String,
+    success: bool,
+    /// Exit code of a script run on a single host.
+    exit_code: Option<i32>,
+    elapsed: Option<Duration>,
+}
+
+impl Outcome {
+    fn failed(message: String) -> Self {
+        Outcome {
+            message,
+            success: false,
+            exit_code: None,
+            elapsed: None,
+        }
+    }
+}
+
+/// A conversation with the model along with the shells its scripts run in.
+pub struct Agent {
+    pub args: Args,
+    pub session_name: String,
+    pub state: State,
+    /// Where scripts run, one per host with `--hosts`.
+    pub backends: Vec<Backend>,
+    /// Piped input and attached files, sent along with the next message.
+    pub context: Option<String>,
+    /// Tokens used by the completions of this run, as reported by the server.
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    endpoints: Endpoints,
+    shells: Vec<Shell>,
+    counter: TokenCounter,
+    tool_mode: ToolMode,
+    terminal_tool: ChatCompletionTool,
+    policy: Policy,
+    interrupt: Interrupt,
+}
+
+impl Agent {
+    pub fn new(
+        args: Args,
+        session_name: String,
+        mut state: State,
+        backends: Vec<Backend>,
+        interrupt: Interrupt,
+    ) -> anyhow::Result<Self> {
+        let mut endpoints = vec![Endpoint::new(&args.api_base, &args.api_key, None)];
+        endpoints.extend(
+            args.fallback
+                .iter()
+                .map(|spec| Endpoint::parse(spec, &args.api_base, &args.api_key)),
+        );
+        let endpoints = Endpoints {
+            list: endpoints,
+            retries: args.retries,
+            timeout: (args.request_timeout > 0).then(|| Duration::from_secs(args.request_timeout)),
+        };
+
+        // Define the terminal tool for API requests
+        let terminal_tool = ChatCompletionTool {
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionObject {
+                name: "terminal".to_string(),
+                description: Some("Run a terminal command and get the output. Maintains current working directory across calls.".to_string()),
+                parameters: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {
+                            "type": "string",
+                            "description": "The command to execute"
+                        }
+                    },
+                    "required": ["command"]
+                })),
+                strict: None,
+            },
+        };
+
+        record_settings(&mut state, &args);
+        let mut shells = Vec::new();
+        if let [backend] = backends.as_slice() {
+            let mut shell = Shell::spawn(backend, state.shell.as_ref())?;
+            if backend.is_remote() {
+                let _ = log::HOST.set(shell.hostname()?);
+            }
+            shells.push(shell);
+        } else {
+            for backend in &backends {
+                let host = backend.target().unwrap_or_default().to_string();
+                let mut shell = Shell::spawn(backend, state.host_shells.get(&host))?;
+                shell.set_label(host);
+                shells.push(shell);
+            }
+        }
+        Ok(Agent {
+            shells,
+            backends,
+            context: None,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            counter: TokenCounter::new(args.tokenizer, &args.model),
+            tool_mode: args.tool_mode,
+            policy: Policy::new(args.allow.clone(), args.deny.clone()),
+            args,
+            session_name,
+            state,
+            endpoints,
+            terminal_tool,
+            interrupt,
+        })
+    }
+
+    /// Saves the conversation and starts a new one in the same shells.
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        self.save()?;
+        self.session_name = session::new_name();
+        self.state = State::new(
+            &prompt::system(&self.backends, self.args.system_prompt.as_deref())?,
+            std::env::current_dir()?,
+        );
+        record_settings(&mut self.state, &self.args);
+        Ok(())
+    }
+
+    pub fn set_model(&mut self, model: &str) {
+        self.args.model = model.to_string();
+        self.state.model = model.to_string();
+        self.counter = TokenCounter::new(self.args.tokenizer, model);
+    }
+
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        if let [shell] = self.shells.as_slice() {
+            self.state.shell = Some(shell.state().clone());
+        } else {
+            self.state.host_shells = self
+                .shells
+                .iter()
+                .map(|shell| {
+                    let host = shell.label().unwrap_or_default().to_string();
+                    (host, shell.state().clone())
+                })
+                .collect();
+        }
+        session::save(&self.session_name, &mut self.state)
+    }
+
+    /// Runs a script the model asked for, unless the policy refuses it or the user declines.
+    ///
+    /// In safe mode the user may edit the script or skip it with feedback first. Returns None
+    /// when the user canceled.
+    async fn run_script(
+        &mut self,
+        proposed: &str,
+        call: Option<&ChatCompletionMessageToolCall>,
+    ) -> anyhow::Result<Option<Outcome>> {
+        let mut script = proposed.to_string();
+        match self.policy.check(proposed) {
+            Verdict::Deny(reason) => {
+                eprintln!("Refused to run the script: {}", reason);
+                log_event("script_denied", call, &reason)?;
+                return Ok(Some(Outcome::failed(format!(
+                    "Error: the script was not run because {}. Find another way or ask the user.",
+                    reason
+                ))));
+            }
+            Verdict::Ask(reason) if self.args.safe => {
+                eprintln!("Needs confirmation: {}", reason);
+                match confirm::ask(proposed, call)? {
+                    Decision::Run(edited) => script = edited,
+                    Decision::AlwaysAllow(edited) => {
+                        self.policy.allow_script(&edited);
+                        script = edited;
+                    }
+                    Decision::Skip(feedback) => {
+                        return Ok(Some(Outcome::failed(format!(
+                            "The user chose not to run the script. Their feedback: {}",
+                            feedback
+                        ))));
+                    }
+                    Decision::Cancel => return Ok(None),
+                }
+            }
+            Verdict::Ask(_) | Verdict::Run => {}
+        }
+
+        let timeout = (self.args.timeout > 0).then(|| Duration::from_secs(self.args.timeout));
+        let mut outcome = if let [shell] = self.shells.as_mut_slice() {
+            let interrupt = &self.interrupt;
+            let output = shell.run(&script, timeout, &|| interrupt.is_set())?;
+            eprintln!("{}", output.summary());
+            if output.interrupted {
+                interrupt.set();
+            }
+            Outcome {
+                message: output.to_message(self.args.max_output),
+                success: output.status == Some(0),
+                exit_code: output.status,
+                elapsed: Some(output.elapsed),
+            }
+        } else {
+            self.fan_out(&script, timeout, call).await?
+        };
+        if script != proposed {
+            outcome.message = format!(
+                "The user edited the script before running it:\n```\n{}\n```\n{}",
+                script, outcome.message
+            );
+        }
+        Ok(Some(outcome))
+    }
+
+    /// Runs a script on every host at once and collects their outputs into one message.
+    async fn fan_out(
+        &mut self,
+        script: &str,
+        timeout: Option<Duration>,
+        call: Option<&ChatCompletionMessageToolCall>,
+    ) -> anyhow::Result<Outcome> {
+        let tasks: Vec<_> = self
+            .shells
+            .drain(..)
+            .map(|mut shell| {
+                let script = script.to_string();
+                let interrupt = self.interrupt.clone();
+                tokio::task::spawn_blocking(move || {
+                    let output = shell.run(&script, timeout, &|| interrupt.is_set());
+                    (shell, output)
+                })
+            })
+            .collect();
+
+        let max_output = self.args.max_output;
+        let mut outcome = Outcome {
+            message: format!("Ran on {} hosts:\n", tasks.len()),
+            success: true,
+            exit_code: None,
+            elapsed: None,
+        };
+        for task in tasks {
+            let (shell, output) = task.await?;
+            let host = shell.label().unwrap_or_default().to_string();
+            let (result, exit_code, elapsed) = match output {
+                Ok(output) => {
+                    eprintln!("[{}] {}", host, output.summary());
+                    outcome.success &= output.status == Some(0);
+                    (
+                        output.to_message(max_output),
+                        output.status,
+                        Some(output.elapsed),
+                    )
+                }
+                Err(e) => {
+                    outcome.success = false;
+                    (format!("Error: {}", e), None, None)
+                }
+            };
+            log::Entry::new("host_output", call, &result)
+                .host(&host)
+                .script(exit_code, elapsed)
+                .write()?;
+            outcome
+                .message
+                .push_str(&format!("\n[{}]\n{}\n", host, result));
+            self.shells.push(shell);
+        }
+        Ok(outcome)
+    }
+
+    /// Sends a user message and keeps going until the model is done with it.
+    pub async fn send(&mut self, mut user_message: String) -> anyhow::Result<TurnEnd> {
+        self.interrupt.reset();
+        self.state.set_title(&user_message);
+        if let Some(context) = self.context.take() {
+            eprintln!("Attachments: {} tokens", self.counter.count(&context));
+            user_message.push_str("\n\n");
+            user_message.push_str(&context);
+        }
+        if self.args.looping {
+            user_message.push_str("\n\nIMPORTANT: To keep processing, provide terminal commands when needed. When fully done, say \"Fully Done Processing\".");
+        }
+        user_message.push_str("If you want to call a script, use terminal_call:\\n```");
+
+        self.state.turns += 1;
+        log::set_turn(&self.session_name, self.state.turns, &self.args.model);
+        self.state.messages.push(ChatCompletionRequestMessage::User(
+            user_message.clone().into(),
+        ));
+        log_event("user", None, &user_message)?;
+
+        let mut iteration = 0;
+        loop {
+            if self.interrupt.is_set() {
+                eprintln!("Interrupted, stopping.");
+                log_event("interrupted", None, "User interrupted")?;
+                return Ok(TurnEnd::Canceled);
+            }
+
+            if self.args.summarize_at > 0
+                && self.counter.count_messages(&self.state.messages) > self.args.summarize_at
+            {
+                match summary::compact(
+                    &self.endpoints,
+                    &self.args.model,
+                    &self.session_name,
+                    &mut self.state,
+                )
+                .await
+                {
+                    Ok(true) => {
+                        eprintln!("Summarized older messages to save context.");
+                        self.save()?;
+                        log_event(
+                            "summarized",
+                            None,
+                            &self
+                                .counter
+                                .count_messages(&self.state.messages)
+                                .to_string(),
+                        )?;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        eprintln!("Summarizing failed: {}", e);
+                        log_event("summary_failed", None, &e.to_string())?;
+                    }
+                }
+            }
+
+            let mut messages = self.state.messages.clone();
+            if self.args.context_budget > 0
+                && tokens::fit_to_budget(&mut messages, &self.counter, self.args.context_budget)
+            {
+                log_event(
+                    "context_trimmed",
+                    None,
+                    &self.counter.count_messages(&messages).to_string(),
+                )?;
+            }
+            let mut request = CreateChatCompletionRequest {
+                model: self.args.model.clone(),
+                messages,
+                ..Default::default()
+            };
+            self.args.sampling.apply(&mut request);
+            if self.tool_mode != ToolMode::Text {
+                request.tools = Some(vec![self.terminal_tool.clone()]);
+            }
+
+            let stream = !self.args.no_stream;
+            // Until tools are known to work, only the first endpoint is tried with them. It
+            // failing may just mean it doesn't support them.
+            let auto = self.tool_mode == ToolMode::Auto;
+            let reply = tokio::select! {
+                reply = self.endpoints.complete_primary(&request, stream), if auto => reply,
+                reply = self.endpoints.complete(&request, stream), if !auto => reply,
+                _ = self.interrupt.wait() => continue,
+            };
+            let (reply, endpoint) = match reply {
+                // Servers without tool support tend to reject the whole request, retry with the text protocol.
+                Err(e) if auto && completion::is_rejection(&e) => {
+                    eprintln!(
+                        "Tool calling rejected ({}), falling back to terminal_call blocks.",
+                        e
+                    );
+                    log_event("tool_fallback", None, &e.to_string())?;
+                    self.tool_mode = ToolMode::Text;
+                    request.tools = None;
+                    tokio::select! {
+                        reply = self.endpoints.complete(&request, stream) => reply?,
+                        _ = self.interrupt.wait() => continue,
+                    }
+                }
+                Err(_) if auto && self.endpoints.list.len() > 1 => tokio::select! {
+                    reply = self.endpoints.complete_fallbacks(&request, stream) => reply?,
+                    _ = self.interrupt.wait() => continue,
+                },
+                reply => reply?,
+            };
+            if self.tool_mode == ToolMode::Auto {
+                // Tools work, later requests may go to any endpoint with them.
+                self.tool_mode = ToolMode::Native;
+            }
+            let model = endpoint.model.as_deref().unwrap_or(&request.model);
+            self.state.model = model.to_string();
+            self.state.api_base = endpoint.api_base.clone();
+            let content = reply.content.as_deref().unwrap_or_default();
+            log::Entry::new("assistant", None, content)
+                .endpoint(&endpoint.api_base, model)
+                .usage(reply.usage.clone())
+                .write()?;
+            output::emit(&Event::Assistant {
+                content,
+                usage: reply.usage.as_ref(),
+            })?;
+            if let Some(usage) = &reply.usage {
+                self.prompt_tokens += usage.prompt_tokens;
+                self.completion_tokens += usage.completion_tokens;
+            }
+
+            let tool_calls = reply.tool_calls.clone();
+            let mut executed = false;
+            if !tool_calls.is_empty() {
+                // Tool results must directly follow the assistant message that requested them.
+                self.state.messages.push(message::assistant(
+                    reply.content.clone(),
+                    Some(tool_calls.clone()),
+                ));
+
+                let mut failed = false;
+                for (i, call) in tool_calls.iter().enumerate() {
+                    let command = terminal_command(call);
+                    let outcome = match &command {
+                        Ok(_) if failed && self.args.stop_on_error => {
+                            Outcome::failed(SKIPPED_AFTER_FAILURE.into())
+                        }
+                        Ok(script) => {
+                            eprintln!("terminal: {}", script);
+                            log_event("tool_call", Some(call), script)?;
+                            output::emit(&Event::Script {
+                                script,
+                                tool_call_id: Some(&call.id),
+                            })?;
+                            match self.run_script(script, Some(call)).await? {
+                                Some(outcome) => outcome,
+                                None => {
+                                    // The tool calls were already stored, they all need answers.
+                                    for call in &tool_calls[i..] {
+                                        self.state.messages.push(message::tool(
+                                            &call.id,
+                                            "Canceled by the user".into(),
+                                        ));
+                                    }
+                                    self.save()?;
+                                    return Ok(TurnEnd::Canceled);
+                                }
+                            }
+                        }
+                        Err(e) => Outcome::failed(format!("Error: {}", e)),
+                    };
+                    failed |= !outcome.success;
+                    report(command.as_deref().unwrap_or_default(), Some(call), &outcome)?;
+
+                    self.state
+                        .messages
+                        .push(message::tool(&call.id, outcome.message));
+                }
+                executed = true;
+            } else {
+                // Stored as is, the results follow as a separate observation.
+                self.state
+                    .messages
+                    .push(message::assistant(reply.content.clone(), None));
+
+                let extract::Calls { scripts, errors } = extract::terminal_calls(content);
+                if !scripts.is_empty() {
+                    // Calculate context length
+                    let context_len = self.counter.count_messages(&self.state.messages);
+                    eprintln!("Current context length: {} tokens", context_len);
+
+                    let mut runs = Vec::new();
+                    let mut failed = false;
+                    for (i, script) in scripts.iter().enumerate() {
+                        if failed && self.args.stop_on_error {
+                            let outcome = Outcome::failed(SKIPPED_AFTER_FAILURE.to_string());
+                            report(script, None, &outcome)?;
+                            runs.push((script.clone(), outcome.message));
+                            continue;
+                        }
+                        if scripts.len() > 1 {
+                            eprintln!("Script {} of {}:\n{}", i + 1, scripts.len(), script);
+                        }
+                        output::emit(&Event::Script {
+                            script,
+                            tool_call_id: None,
+                        })?;
+                        let Some(outcome) = self.run_script(script, None).await? else {
+                            // Keep what already ran, the model should know about it.
+                            if !runs.is_empty() {
+                                self.state.messages.push(message::script_output(&runs));
+                                self.save()?;
+                            }
+                            return Ok(TurnEnd::Canceled);
+                        };
+                        report(script, None, &outcome)?;
+                        failed |= !outcome.success;
+                        runs.push((script.clone(), outcome.message));
+                    }
+
+                    // Append output to conversation
+                    self.state.messages.push(message::script_output(&runs));
+                    executed = true;
+                }
+                if !errors.is_empty() {
+                    // Tell the model, otherwise it would wait for output that never comes.
+                    for error in &errors {
+                        eprintln!("Malformed terminal_call: {}", error);
+                        log_event("malformed_call", None, error)?;
+                    }
+                    self.state.messages.push(message::malformed_calls(&errors));
+                    executed = true;
+                }
+            }
+
+            self.save()?;
+
+            if !self.args.looping || content.to_lowercase().contains("fully done processing") {
+                return Ok(TurnEnd::Finished);
+            }
+
+            iteration += 1;
+            if iteration >= self.args.max_iterations {
+                eprintln!(
+                    "Reached the maximum of {} iterations, stopping.",
+                    self.args.max_iterations
+                );
+                log_event("max_iterations", None, &iteration.to_string())?;
+                return Ok(TurnEnd::OutOfIterations);
+            }
+
+            // Nothing was executed, so the model is waiting on the user.
+            if !executed {
+                return Ok(TurnEnd::WaitingForUser);
+            }
+        }
+    }
+}
+
+/// Records what running a script gave in the log and, for `--output-format jsonl`, on stdout.
+fn report(
+    script: &str,
+    call: Option<&ChatCompletionMessageToolCall>,
+    outcome: &Outcome,
+) -> anyhow::Result<()> {
+    log::Entry::new("script_output", call, &outcome.message)
+        .script(outcome.exit_code, outcome.elapsed)
+        .write()?;
+    output::emit(&Event::Result {
+        script,
+        output: &outcome.message,
+        success: outcome.success,
+        exit_code: outcome.exit_code,
+        duration_ms: output::millis(outcome.elapsed),
+    })
+}
+
+/// Extracts the command argument of a terminal tool call.
+fn terminal_command(call: &ChatCompletionMessageToolCall) -> anyhow::Result<String> {
+    if call.function.name != "terminal" {
+        anyhow::bail!("unknown tool `{}`", call.function.name);
+    }
+    let args: serde_json::Value = serde_json::from_str(&call.function.arguments)?;
+    args["command"]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or(anyhow::anyhow!("missing `command` argument"))
+}
@@ -0,0 +1,97 @@
+//! Context sent along with a message: piped stdin and `--file` attachments.
+
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::PathBuf;
+
+use crate::text;
+
+/// Reads all of stdin if something is piped into it.
+///
+/// Empty input, like from `/dev/null`, counts as nothing piped.
+pub fn piped_stdin() -> anyhow::Result<Option<Vec<u8>>> {
+    if io::stdin().is_terminal() {
+        return Ok(None);
+    }
+    let mut bytes = Vec::new();
+    io::stdin().lock().read_to_end(&mut bytes)?;
+    Ok((!bytes.is_empty()).then_some(bytes))
+}
+
+/// The files matching the patterns, in order and without duplicates.
+///
+/// A pattern without glob characters is taken as a plain path, so it has to exist.
+pub fn expand(patterns: &[String]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let matches = if pattern.contains(['*', '?', '[']) {
+            let mut matches = Vec::new();
+            for path in glob::glob(pattern)
+                .map_err(|e| anyhow::anyhow!("Invalid pattern `{}`: {}", pattern, e))?
+            {
+                let path = path?;
+                if path.is_file() {
+                    matches.push(path);
+                }
+            }
+            if matches.is_empty() {
+                anyhow::bail!("No file matches `{}`", pattern);
+            }
+            matches
+        } else {
+            let path = PathBuf::from(pattern);
+            if !path.is_file() {
+                anyhow::bail!("Can't attach {}: not a file", pattern);
+            }
+            vec![path]
+        };
+        for path in matches {
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// The contents of the files as labeled blocks, each kept under `limit` bytes.
+pub fn files(paths: &[PathBuf], limit: usize) -> anyhow::Result<String> {
+    let mut blocks = Vec::new();
+    for path in paths {
+        let bytes =
+            fs::read(path).map_err(|e| anyhow::anyhow!("Can't read {}: {}", path.display(), e))?;
+        eprintln!("Attached {} ({} bytes)", path.display(), bytes.len());
+        blocks.push(block(&format!("File {}", path.display()), &bytes, limit));
+    }
+    Ok(blocks.join("\n\n"))
+}
+
+/// Piped input as a labeled block, kept under `limit` bytes.
+pub fn stdin(input: &[u8], limit: usize) -> String {
+    eprintln!("Attached stdin ({} bytes)", input.len());
+    block("Piped input", input, limit)
+}
+
+/// The contents in a code fence long enough not to be closed by anything inside them.
+///
+/// Binary data is only described.
+fn block(label: &str, bytes: &[u8], limit: usize) -> String {
+    if text::is_binary(bytes) {
+        return format!("{}: [binary data, {} bytes, not shown]", label, bytes.len());
+    }
+    let contents = text::shorten(&String::from_utf8_lossy(bytes), limit);
+    let mut longest = 0;
+    let mut run = 0;
+    for c in contents.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat((longest + 1).max(3));
+    format!(
+        "{}:\n{}\n{}\n{}",
+        label,
+        fence,
+        contents.trim_end_matches('\n'),
+        fence
+    )
+}
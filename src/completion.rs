@@ -0,0 +1,477 @@
+use async_openai::{
+    Client,
+    config::OpenAIConfig,
+    error::{ApiError, OpenAIError},
+    types::{
+        ChatCompletionMessageToolCall, ChatCompletionStreamOptions, ChatCompletionToolType,
+        CompletionUsage, CreateChatCompletionRequest, CreateChatCompletionResponse, FunctionCall,
+    },
+};
+use futures::StreamExt;
+use rand::Rng;
+use std::future::Future;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::{log_event, output};
+
+/// Longest wait between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The assistant's answer to one completion request.
+#[derive(Debug, Clone, Default)]
+pub struct Reply {
+    pub content: Option<String>,
+    pub tool_calls: Vec<ChatCompletionMessageToolCall>,
+    /// Tokens used, if the server reported them.
+    pub usage: Option<CompletionUsage>,
+}
+
+/// Where completions are requested from.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub api_base: String,
+    /// The model to use instead of the one of the request.
+    pub model: Option<String>,
+    client: Client<OpenAIConfig>,
+}
+
+impl Endpoint {
+    pub fn new(api_base: &str, api_key: &str, model: Option<String>) -> Self {
+        let config = OpenAIConfig::new()
+            .with_api_base(api_base)
+            .with_api_key(api_key);
+        Endpoint {
+            api_base: api_base.to_string(),
+            model,
+            // Retrying is up to `Endpoints`, async-openai would otherwise keep retrying server
+            // errors for minutes on its own.
+            client: Client::with_config(config).with_backoff(backoff::ExponentialBackoff {
+                max_elapsed_time: Some(Duration::ZERO),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Parses a `--fallback`: a model, a model on another server as `MODEL@API_BASE`, or
+    /// `@API_BASE` for the same model on another server.
+    pub fn parse(spec: &str, api_base: &str, api_key: &str) -> Self {
+        let (model, base) = match spec.split_once('@') {
+            Some((model, base)) if base.starts_with("http") => (model, base),
+            _ => (spec, api_base),
+        };
+        Endpoint::new(
+            base,
+            api_key,
+            (!model.is_empty()).then(|| model.to_string()),
+        )
+    }
+}
+
+/// The endpoints to try in order, and how often to retry each one.
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    pub list: Vec<Endpoint>,
+    /// Attempts after the first one on each endpoint.
+    pub retries: u32,
+    /// How long the server may take to start answering, and to send the next chunk of a
+    /// streamed answer, before the request counts as failed.
+    pub timeout: Option<Duration>,
+}
+
+impl Endpoints {
+    /// Sends the request like [`complete`], retrying transient errors with exponential backoff
+    /// and moving on to the next endpoint once an endpoint runs out of retries.
+    ///
+    /// Returns the reply and the endpoint that gave it.
+    pub async fn complete(
+        &self,
+        request: &CreateChatCompletionRequest,
+        stream: bool,
+    ) -> Result<(Reply, &Endpoint), OpenAIError> {
+        self.complete_on(&self.list, request, stream).await
+    }
+
+    /// Like [`Endpoints::complete`], but only on the first endpoint.
+    pub async fn complete_primary(
+        &self,
+        request: &CreateChatCompletionRequest,
+        stream: bool,
+    ) -> Result<(Reply, &Endpoint), OpenAIError> {
+        self.complete_on(&self.list[..1], request, stream).await
+    }
+
+    /// Like [`Endpoints::complete`], but skipping the first endpoint.
+    pub async fn complete_fallbacks(
+        &self,
+        request: &CreateChatCompletionRequest,
+        stream: bool,
+    ) -> Result<(Reply, &Endpoint), OpenAIError> {
+        self.complete_on(&self.list[1..], request, stream).await
+    }
+
+    async fn complete_on<'a>(
+        &'a self,
+        list: &'a [Endpoint],
+        request: &CreateChatCompletionRequest,
+        stream: bool,
+    ) -> Result<(Reply, &'a Endpoint), OpenAIError> {
+        self.attempt(list, request, |client, request| async move {
+            complete(&client, request, stream, self.timeout).await
+        })
+        .await
+    }
+
+    /// Sends the request without printing anything, with the same retries as
+    /// [`Endpoints::complete`].
+    pub async fn create(
+        &self,
+        request: &CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, OpenAIError> {
+        let (response, _) = self
+            .attempt(&self.list, request, |client, request| async move {
+                timed(self.timeout, client.chat().create(request)).await
+            })
+            .await?;
+        Ok(response)
+    }
+
+    async fn attempt<'a, T, F, Fut>(
+        &'a self,
+        list: &'a [Endpoint],
+        request: &CreateChatCompletionRequest,
+        send: F,
+    ) -> Result<(T, &'a Endpoint), OpenAIError>
+    where
+        F: Fn(Client<OpenAIConfig>, CreateChatCompletionRequest) -> Fut,
+        Fut: Future<Output = Result<T, OpenAIError>>,
+    {
+        let mut last_error = None;
+        for endpoint in list {
+            if !std::ptr::eq(endpoint, &self.list[0]) {
+                eprintln!(
+                    "Falling back to {} at {}",
+                    endpoint.model.as_deref().unwrap_or(&request.model),
+                    endpoint.api_base
+                );
+            }
+            let mut request = request.clone();
+            if let Some(model) = &endpoint.model {
+                request.model = model.clone();
+            }
+            for attempt in 0..=self.retries {
+                let error = match send(endpoint.client.clone(), request.clone()).await {
+                    Ok(value) => return Ok((value, endpoint)),
+                    Err(e) if !is_transient(&e) => return Err(e),
+                    Err(e) => e,
+                };
+                let _ = log_event(
+                    "request_failed",
+                    None,
+                    &format!("{}: {}", endpoint.api_base, error),
+                );
+                if attempt < self.retries {
+                    let delay = backoff(attempt);
+                    eprintln!(
+                        "Request to {} failed ({}), retrying in {:.1}s",
+                        endpoint.api_base,
+                        error,
+                        delay.as_secs_f64()
+                    );
+                    tokio::time::sleep(delay).await;
+                } else {
+                    eprintln!("Request to {} failed ({})", endpoint.api_base, error);
+                }
+                last_error = Some(error);
+            }
+        }
+        Err(last_error.unwrap_or(OpenAIError::InvalidArgument(
+            "No endpoint to send to".into(),
+        )))
+    }
+}
+
+/// Fails with a timeout error if `future` takes longer than `timeout`.
+async fn timed<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, OpenAIError>>,
+) -> Result<T, OpenAIError> {
+    let Some(timeout) = timeout else {
+        return future.await;
+    };
+    tokio::time::timeout(timeout, future)
+        .await
+        .unwrap_or_else(|_| {
+            Err(OpenAIError::StreamError(format!(
+                "No response for {}s",
+                timeout.as_secs()
+            )))
+        })
+}
+
+/// Whether the request may work when sent again: the server couldn't be reached, timed out,
+/// answered with a server error or was rate limited.
+///
+/// Errors about the request itself, like a model that doesn't support tools, are not transient.
+pub fn is_transient(error: &OpenAIError) -> bool {
+    match error {
+        OpenAIError::Reqwest(_) | OpenAIError::JSONDeserialize(_) => true,
+        // Server errors come without a type, see `Endpoint::new`.
+        OpenAIError::ApiError(e) => e.r#type.is_none() || is_rate_limit(e),
+        OpenAIError::StreamError(message) => match message.strip_prefix("Invalid status code: ") {
+            Some(status) => ["5", "408", "429"]
+                .iter()
+                .any(|prefix| status.starts_with(prefix)),
+            None => !message.starts_with("Invalid header value"),
+        },
+        _ => false,
+    }
+}
+
+/// Whether the server refused the request itself with a client error, other than a timeout or
+/// a rate limit.
+///
+/// Servers without tool support reject requests offering tools like that. A server error only
+/// says the server is in trouble, not that tools are the problem.
+pub fn is_rejection(error: &OpenAIError) -> bool {
+    match error {
+        // Server errors come without a type, see `Endpoint::new`.
+        OpenAIError::ApiError(e) => e.r#type.is_some() && !is_rate_limit(e),
+        OpenAIError::StreamError(message) => message
+            .strip_prefix("Invalid status code: ")
+            .is_some_and(|status| {
+                status.starts_with('4')
+                    && !["408", "429"].iter().any(|code| status.starts_with(code))
+            }),
+        _ => false,
+    }
+}
+
+fn is_rate_limit(error: &ApiError) -> bool {
+    [&error.r#type, &error.code]
+        .iter()
+        .any(|field| field.as_deref().is_some_and(|s| s.contains("rate_limit")))
+}
+
+/// Exponential backoff with jitter: around 1s, 2s, 4s... up to [`MAX_BACKOFF`].
+fn backoff(attempt: u32) -> Duration {
+    let delay = Duration::from_secs(1 << attempt.min(5)).min(MAX_BACKOFF);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..1.0))
+}
+
+/// Sends the request and prints the assistant text.
+///
+/// When streaming, tokens are printed as they arrive and the content and tool calls are assembled
+/// from the chunks, so the caller sees the same `Reply` either way. `timeout` limits the wait for
+/// the response, or when streaming for each chunk, so slow models can take as long as they
+/// keep sending.
+pub async fn complete(
+    client: &Client<OpenAIConfig>,
+    mut request: CreateChatCompletionRequest,
+    stream: bool,
+    timeout: Option<Duration>,
+) -> Result<Reply, OpenAIError> {
+    if !stream {
+        let response = timed(timeout, client.chat().create(request)).await?;
+        let usage = response.usage;
+        let message = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(OpenAIError::InvalidArgument("No choices returned".into()))?
+            .message;
+        if !output::is_json() {
+            println!("{}", message.content.as_deref().unwrap_or_default());
+        }
+        return Ok(Reply {
+            content: message.content,
+            tool_calls: message.tool_calls.unwrap_or_default(),
+            usage,
+        });
+    }
+
+    // Usage comes in a last chunk without choices, only when asked for.
+    request.stream_options = Some(ChatCompletionStreamOptions {
+        include_usage: true,
+    });
+    let mut chunks = timed(timeout, client.chat().create_stream(request)).await?;
+    let mut content = String::new();
+    let mut tool_calls: Vec<ChatCompletionMessageToolCall> = Vec::new();
+    let mut usage = None;
+    loop {
+        let chunk = match timed(timeout, async { Ok(chunks.next().await) }).await {
+            Ok(None) => break,
+            Ok(Some(Ok(chunk))) => chunk,
+            Ok(Some(Err(e))) | Err(e) if !content.is_empty() && !output::is_json() => {
+                // Makes clear that the printed text is incomplete, it is printed again when the
+                // request is retried.
+                println!();
+                let reason = match e {
+                    OpenAIError::StreamError(reason) => reason,
+                    e => e.to_string(),
+                };
+                return Err(OpenAIError::StreamError(format!(
+                    "response cut off after {} bytes: {}",
+                    content.len(),
+                    reason
+                )));
+            }
+            Ok(Some(Err(e))) | Err(e) => return Err(e),
+        };
+        if chunk.usage.is_some() {
+            usage = chunk.usage;
+        }
+        let Some(choice) = chunk.choices.into_iter().next() else {
+            continue;
+        };
+
+        if let Some(text) = choice.delta.content {
+            if !output::is_json() {
+                print!("{}", text);
+                let _ = io::stdout().flush();
+            }
+            content.push_str(&text);
+        }
+
+        // Tool calls arrive in pieces keyed by index, the arguments string is split across chunks.
+        for part in choice.delta.tool_calls.unwrap_or_default() {
+            let index = part.index as usize;
+            while tool_calls.len() <= index {
+                tool_calls.push(ChatCompletionMessageToolCall {
+                    id: String::new(),
+                    r#type: ChatCompletionToolType::Function,
+                    function: FunctionCall {
+                        name: String::new(),
+                        arguments: String::new(),
+                    },
+                });
+            }
+            let call = &mut tool_calls[index];
+            if let Some(id) = part.id {
+                call.id.push_str(&id);
+            }
+            if let Some(function) = part.function {
+                call.function
+                    .name
+                    .push_str(function.name.as_deref().unwrap_or_default());
+                call.function
+                    .arguments
+                    .push_str(function.arguments.as_deref().unwrap_or_default());
+            }
+        }
+    }
+    if !output::is_json() {
+        println!();
+    }
+
+    Ok(Reply {
+        content: (!content.is_empty()).then_some(content),
+        tool_calls,
+        usage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(r#type: Option<&str>, code: Option<&str>) -> OpenAIError {
+        OpenAIError::ApiError(ApiError {
+            message: "failed".into(),
+            r#type: r#type.map(str::to_string),
+            param: None,
+            code: code.map(str::to_string),
+        })
+    }
+
+    fn stream_error(message: &str) -> OpenAIError {
+        OpenAIError::StreamError(message.to_string())
+    }
+
+    #[test]
+    fn classifies_errors() {
+        // Error, whether it is retried, whether it means tools aren't supported.
+        let cases = [
+            (api_error(None, None), true, false),
+            (api_error(Some("invalid_request_error"), None), false, true),
+            (api_error(Some("rate_limit_exceeded"), None), true, false),
+            (
+                api_error(Some("requests"), Some("rate_limit_exceeded")),
+                true,
+                false,
+            ),
+            (
+                stream_error("Invalid status code: 500 Internal Server Error"),
+                true,
+                false,
+            ),
+            (
+                stream_error("Invalid status code: 502 Bad Gateway"),
+                true,
+                false,
+            ),
+            (
+                stream_error("Invalid status code: 400 Bad Request"),
+                false,
+                true,
+            ),
+            (
+                stream_error("Invalid status code: 404 Not Found"),
+                false,
+                true,
+            ),
+            (
+                stream_error("Invalid status code: 408 Request Timeout"),
+                true,
+                false,
+            ),
+            (
+                stream_error("Invalid status code: 429 Too Many Requests"),
+                true,
+                false,
+            ),
+            (stream_error("No response for 300s"), true, false),
+            (
+                stream_error("response cut off after 12 bytes: reset"),
+                true,
+                false,
+            ),
+            (
+                stream_error("Invalid header value: \"text/html\""),
+                false,
+                false,
+            ),
+            (
+                OpenAIError::JSONDeserialize(serde_json::from_str::<u32>("x").unwrap_err()),
+                true,
+                false,
+            ),
+            (OpenAIError::InvalidArgument("bad".into()), false, false),
+        ];
+        for (error, transient, rejection) in cases {
+            assert_eq!(is_transient(&error), transient, "retry {:?}", error);
+            assert_eq!(is_rejection(&error), rejection, "rejection {:?}", error);
+        }
+    }
+
+    #[test]
+    fn parses_fallbacks() {
+        const BASE: &str = "http://localhost:8080/v1";
+        // Spec, API base, model.
+        let cases = [
+            ("qwen", BASE, Some("qwen")),
+            (
+                "qwen@http://ai3:8080/v1",
+                "http://ai3:8080/v1",
+                Some("qwen"),
+            ),
+            ("@https://ai3/v1", "https://ai3/v1", None),
+            ("org/model@v2", BASE, Some("org/model@v2")),
+        ];
+        for (spec, api_base, model) in cases {
+            let endpoint = Endpoint::parse(spec, BASE, "key");
+            assert_eq!(endpoint.api_base, api_base, "API base of {:?}", spec);
+            assert_eq!(endpoint.model.as_deref(), model, "model of {:?}", spec);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::sandbox::Sandbox;
+use crate::tokens::Tokenizer;
+
+/// Name of the profile used when neither `--profile` nor `default_profile` pick one.
+const DEFAULT_PROFILE: &str = "default";
+
+/// Contents of `config.toml` in the ai_cli config directory.
+///
+/// ```toml
+/// default_profile = "work"
+///
+/// [profiles.work]
+/// api_base = "http://ai3:8080/v1"
+/// model = "qwen_coder"
+/// safe = true
+/// allow = ["cargo build", "make"]
+/// deny = ["git push"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Defaults for the command line flags. Anything left out keeps the built-in default.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub api_base: Option<String>,
+    pub api_key: Option<String>,
+    pub model: Option<String>,
+    /// Added to `--fallback`.
+    #[serde(default)]
+    pub fallback: Vec<String>,
+    pub retries: Option<u32>,
+    pub request_timeout: Option<u64>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    pub seed: Option<i64>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub safe: Option<bool>,
+    pub looping: Option<bool>,
+    pub max_output: Option<usize>,
+    pub max_attachment: Option<usize>,
+    pub context_budget: Option<usize>,
+    pub summarize_at: Option<usize>,
+    pub tokenizer: Option<Tokenizer>,
+    pub sandbox: Option<Sandbox>,
+    pub sandbox_image: Option<String>,
+    pub sandbox_dir: Option<PathBuf>,
+    pub inventory: Option<PathBuf>,
+    pub system_prompt: Option<PathBuf>,
+    /// Added to `--allow`.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Added to `--deny`.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl Config {
+    /// Location of the config file, usually `~/.config/ai_cli/config.toml`.
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("ai_cli").join("config.toml"))
+    }
+
+    /// Reads the config file, a missing file is the same as an empty one.
+    pub fn load() -> anyhow::Result<Config> {
+        let Some(path) = Config::path().filter(|path| path.exists()) else {
+            return Ok(Config::default());
+        };
+        let s = fs::read_to_string(&path)?;
+        toml::from_str(&s).map_err(|e| anyhow::anyhow!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// Picks the profile to use. Asking for a profile that doesn't exist is an error, having no
+    /// profile at all is not.
+    pub fn profile(&self, name: Option<&str>) -> anyhow::Result<Profile> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .cloned()
+                .ok_or(anyhow::anyhow!("Unknown profile `{}`", name)),
+            None => Ok(self
+                .profiles
+                .get(DEFAULT_PROFILE)
+                .cloned()
+                .unwrap_or_default()),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use async_openai::types::ChatCompletionMessageToolCall;
+use similar::TextDiff;
+use std::fs;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::process::Command;
+
+use crate::log_event;
+
+const CHOICES: &str =
+    "Execute script? [Y]es, [n]o, [e]dit, [s]kip with feedback, [d]iff, [a]lways allow: ";
+
+/// What the user chose to do with a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Run this script, which is the edited one if the user changed it.
+    Run(String),
+    /// Run it, and don't ask again for the same commands in this session.
+    AlwaysAllow(String),
+    /// Don't run it and tell the model why.
+    Skip(String),
+    /// Stop processing altogether.
+    Cancel,
+}
+
+/// Asks what to do with a script until the user picks something final.
+///
+/// Every decision, including edits, is recorded with [`log_event`].
+pub fn ask(script: &str, call: Option<&ChatCompletionMessageToolCall>) -> anyhow::Result<Decision> {
+    let mut current = script.to_string();
+    loop {
+        eprint!("{}", CHOICES);
+        io::stderr().flush()?;
+        let mut input = String::new();
+        // End of input counts as no.
+        if read_answer(&mut input)? == 0 {
+            log_event("script_canceled", call, "End of input")?;
+            return Ok(Decision::Cancel);
+        }
+        match input.trim().to_lowercase().as_str() {
+            "" | "y" | "yes" => {
+                log_event("script_approved", call, &current)?;
+                return Ok(Decision::Run(current));
+            }
+            "n" | "no" => {
+                log_event("script_canceled", call, "User canceled")?;
+                return Ok(Decision::Cancel);
+            }
+            "e" | "edit" => match edit(&current) {
+                Ok(edited) => {
+                    current = edited;
+                    eprintln!("Edited script:\n{}", current);
+                    log_event("script_edited", call, &current)?;
+                }
+                Err(e) => eprintln!("Editing failed: {}", e),
+            },
+            "s" | "skip" => {
+                eprint!("Feedback for the model: ");
+                io::stderr().flush()?;
+                let mut feedback = String::new();
+                read_answer(&mut feedback)?;
+                let feedback = feedback.trim().to_string();
+                log_event("script_skipped", call, &feedback)?;
+                return Ok(Decision::Skip(feedback));
+            }
+            "d" | "diff" => {
+                if current == script {
+                    eprintln!("The script hasn't been edited.");
+                } else {
+                    eprint!(
+                        "{}",
+                        TextDiff::from_lines(script, &current)
+                            .unified_diff()
+                            .header("proposed", "edited")
+                    );
+                }
+            }
+            "a" | "always" => {
+                log_event("script_always_allowed", call, &current)?;
+                return Ok(Decision::AlwaysAllow(current));
+            }
+            other => eprintln!("Unknown choice `{}`", other),
+        }
+    }
+}
+
+/// Reads a line of the answer, from the terminal when stdin is piped into ai_cli.
+fn read_answer(buffer: &mut String) -> io::Result<usize> {
+    if !io::stdin().is_terminal()
+        && let Ok(tty) = fs::File::open("/dev/tty")
+    {
+        return BufReader::new(tty).read_line(buffer);
+    }
+    io::stdin().read_line(buffer)
+}
+
+/// Opens the script in `$VISUAL` or `$EDITOR`, `vi` if neither is set, and returns the result.
+fn edit(script: &str) -> anyhow::Result<String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or("vi".to_string());
+    let path = std::env::temp_dir().join(format!("ai_cli_edit_{:016x}.sh", rand::random::<u64>()));
+    fs::write(&path, script)?;
+    // Through the shell so editors configured with arguments, like `code --wait`, work.
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&path)
+        .status();
+    let edited = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+    if !status?.success() {
+        anyhow::bail!("{} exited with an error", editor);
+    }
+    Ok(edited?)
+}
@@ -0,0 +1,215 @@
+//! Finds the `terminal_call:` blocks in a response.
+//!
+//! Models don't always stick to the exact format from the system prompt, so this accepts the
+//! usual variations: language tags (```` ```sh ````), `~~~` fences, indented or decorated markers
+//! like `**terminal_call:**`, CRLF line endings, inline code (`` terminal_call: `ls` ``) and a
+//! final block whose closing fence was cut off.
+
+const MARKER: &str = "terminal_call";
+/// Markdown emphasis that may surround the marker.
+const DECORATION: &[char] = &['*', '_', '`', '#', '>', '-', ' ', '\t'];
+
+/// What was found in a response.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Calls {
+    /// Scripts to run, in order.
+    pub scripts: Vec<String>,
+    /// Calls that couldn't be read, to tell the model about.
+    pub errors: Vec<String>,
+}
+
+/// An opening code fence.
+struct Fence<'a> {
+    indent: usize,
+    char: char,
+    len: usize,
+    info: &'a str,
+}
+
+pub fn terminal_calls(content: &str) -> Calls {
+    let content = content.replace("\r\n", "\n").replace('\r', "\n");
+    let lines: Vec<&str> = content.lines().collect();
+    let mut calls = Calls::default();
+
+    let mut i = 0;
+    while i < lines.len() {
+        let Some(rest) = marker(lines[i]) else {
+            i += 1;
+            continue;
+        };
+        i += 1;
+
+        if let Some(script) = inline_code(rest) {
+            calls.scripts.push(script.to_string());
+            continue;
+        }
+        // The fence may follow the marker on the same line or after blank lines.
+        let opening = match fence(rest) {
+            Some(fence) => Some(fence),
+            None => {
+                while i < lines.len() && lines[i].trim().is_empty() {
+                    i += 1;
+                }
+                let fence = lines.get(i).and_then(|line| fence(line));
+                if fence.is_some() {
+                    i += 1;
+                }
+                fence
+            }
+        };
+        let Some(opening) = opening else {
+            calls.errors.push(format!(
+                "`{}:` must be followed by a fenced code block with the script",
+                MARKER
+            ));
+            continue;
+        };
+        if opening.char == '`' && opening.info.contains('`') {
+            calls.errors.push(format!(
+                "The code block after `{}:` has a malformed opening fence",
+                MARKER
+            ));
+            continue;
+        }
+
+        let mut body = Vec::new();
+        while i < lines.len() && !closes(lines[i], &opening) {
+            body.push(strip_indent(lines[i], opening.indent));
+            i += 1;
+        }
+        // Past the closing fence, if there was one.
+        i += 1;
+
+        while body.last().is_some_and(|line| line.trim().is_empty()) {
+            body.pop();
+        }
+        let script = body.join("\n");
+        if script.trim().is_empty() {
+            calls
+                .errors
+                .push(format!("The `{}:` block is empty", MARKER));
+        } else {
+            calls.scripts.push(script);
+        }
+    }
+    calls
+}
+
+/// If the line starts with the marker, returns what follows its colon.
+fn marker(line: &str) -> Option<&str> {
+    let line = line.trim_start_matches(DECORATION);
+    if !line
+        .get(..MARKER.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(MARKER))
+    {
+        return None;
+    }
+    let rest = line[MARKER.len()..].trim_start_matches(['*', '_', '`']);
+    let rest = rest.strip_prefix(':')?;
+    Some(rest.trim_start_matches(['*', '_']).trim())
+}
+
+/// A script given as inline code right after the marker.
+fn inline_code(rest: &str) -> Option<&str> {
+    let code = rest.strip_prefix('`')?.strip_suffix('`')?;
+    (!code.is_empty() && !code.contains('`')).then(|| code.trim())
+}
+
+fn fence(line: &str) -> Option<Fence<'_>> {
+    let trimmed = line.trim_start_matches(' ');
+    let indent = line.len() - trimmed.len();
+    let char = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = trimmed.chars().take_while(|c| *c == char).count();
+    (len >= 3).then(|| Fence {
+        indent,
+        char,
+        len,
+        info: trimmed[len..].trim(),
+    })
+}
+
+fn closes(line: &str, opening: &Fence) -> bool {
+    let trimmed = line.trim();
+    let len = trimmed.chars().take_while(|c| *c == opening.char).count();
+    len >= opening.len && trimmed.len() == len
+}
+
+/// Removes up to `indent` leading spaces, the indentation of the opening fence.
+fn strip_indent(line: &str, indent: usize) -> &str {
+    let spaces = line.len() - line.trim_start_matches(' ').len();
+    &line[spaces.min(indent)..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Response, scripts found, number of errors.
+    const CASES: &[(&str, &[&str], usize)] = &[
+        ("terminal_call:\n```\nls -la\n```", &["ls -la"], 0),
+        (
+            "Sure.\n\nterminal_call:\n```\nls\npwd\n```\nDone.",
+            &["ls\npwd"],
+            0,
+        ),
+        ("terminal_call:\n```sh\nls\n```", &["ls"], 0),
+        (
+            "**Terminal_Call:**\r\n```bash\r\necho ok\r\n```\r\n",
+            &["echo ok"],
+            0,
+        ),
+        ("terminal_call:\n```bash\nls\n```", &["ls"], 0),
+        ("terminal_call:\n``` shell\nls\n```", &["ls"], 0),
+        (
+            "terminal_call:\r\n```\r\nls\r\npwd\r\n```\r\n",
+            &["ls\npwd"],
+            0,
+        ),
+        ("terminal_call:  \n\n```\nls\n```", &["ls"], 0),
+        (
+            "  terminal_call:\n  ```\n  ls\n    pwd\n  ```",
+            &["ls\n  pwd"],
+            0,
+        ),
+        ("**terminal_call:**\n```\nls\n```", &["ls"], 0),
+        ("`terminal_call`:\n```\nls\n```", &["ls"], 0),
+        ("### Terminal_Call:\n```\nls\n```", &["ls"], 0),
+        ("terminal_call: ```sh\nls\n```", &["ls"], 0),
+        ("terminal_call: `uname -a`", &["uname -a"], 0),
+        ("terminal_call:\n~~~\nls\n~~~", &["ls"], 0),
+        ("terminal_call:\n````\necho '```'\n````", &["echo '```'"], 0),
+        ("terminal_call:\n```\nls\n", &["ls"], 0),
+        ("terminal_call:\n```\nls\n\n\n", &["ls"], 0),
+        (
+            "terminal_call:\n```\nls\n```\nthen\nterminal_call:\n```\npwd\n```",
+            &["ls", "pwd"],
+            0,
+        ),
+        (
+            "terminal_call:\n```\ncat <<EOF\nhi\nEOF\n```",
+            &["cat <<EOF\nhi\nEOF"],
+            0,
+        ),
+        ("No commands needed.", &[], 0),
+        ("Use a terminal_call: block to run things.", &[], 0),
+        ("```\nls\n```", &[], 0),
+        ("terminal_call:\nls -la", &[], 1),
+        ("terminal_call:", &[], 1),
+        ("terminal_call:\n```\n```", &[], 1),
+        ("terminal_call:\n```\n   \n```", &[], 1),
+        (
+            "terminal_call:\n```\nls\n```\nterminal_call:\npwd",
+            &["ls"],
+            1,
+        ),
+    ];
+
+    #[test]
+    fn extracts_terminal_calls() {
+        for (content, scripts, errors) in CASES {
+            let calls = terminal_calls(content);
+            assert_eq!(calls.scripts, *scripts, "scripts of {:?}", content);
+            assert_eq!(calls.errors.len(), *errors, "errors of {:?}", content);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Groups of SSH destinations that `--hosts` runs scripts on, from `inventory.toml`.
+///
+/// ```toml
+/// web = ["root@web1", "root@web2"]
+/// db = ["admin@db1"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Inventory {
+    #[serde(flatten)]
+    groups: BTreeMap<String, Vec<String>>,
+}
+
+impl Inventory {
+    /// Location of the inventory, usually `~/.config/ai_cli/inventory.toml`.
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("ai_cli").join("inventory.toml"))
+    }
+
+    /// Reads the inventory from `path`, or the default location.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Inventory> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => Inventory::path().ok_or(anyhow::anyhow!("No config directory"))?,
+        };
+        let s = fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Can't read inventory {}: {}", path.display(), e))?;
+        toml::from_str(&s)
+            .map_err(|e| anyhow::anyhow!("Invalid inventory {}: {}", path.display(), e))
+    }
+
+    /// The hosts of a group, or of several separated by commas.
+    pub fn hosts(&self, groups: &str) -> anyhow::Result<Vec<String>> {
+        let mut hosts = Vec::new();
+        for group in groups.split(',').map(str::trim) {
+            let members = self
+                .groups
+                .get(group)
+                .ok_or(anyhow::anyhow!("Unknown host group `{}`", group))?;
+            for host in members {
+                if !hosts.contains(host) {
+                    hosts.push(host.clone());
+                }
+            }
+        }
+        if hosts.is_empty() {
+            anyhow::bail!("Host group `{}` is empty", groups);
+        }
+        Ok(hosts)
+    }
+}
@@ -0,0 +1,295 @@
+//! The event log: one JSON object per line in `~/.local/share/ai_cli/log.jsonl`.
+
+use async_openai::types::{ChatCompletionMessageToolCall, CompletionUsage};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// Host recorded with each event when scripts run somewhere else than here.
+pub static HOST: OnceLock<String> = OnceLock::new();
+/// Session, turn and model the following events belong to, see [`set_turn`].
+static CONTEXT: Mutex<Context> = Mutex::new(Context {
+    session: None,
+    turn: None,
+    model: None,
+});
+
+struct Context {
+    session: Option<String>,
+    turn: Option<usize>,
+    model: Option<String>,
+}
+
+/// One line of the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub timestamp: DateTime<Utc>,
+    pub event: String,
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub turn: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// API base a completion came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_base: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<String>,
+    pub details: String,
+    /// Token usage reported by the API for a completion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+impl Entry {
+    /// An event on this machine, or on [`HOST`] if it is set, in the turn given to [`set_turn`].
+    pub fn new(
+        event: &str,
+        tool_call: Option<&ChatCompletionMessageToolCall>,
+        details: &str,
+    ) -> Self {
+        let host = match HOST.get() {
+            Some(host) => host.clone(),
+            None => sys_info::hostname().unwrap_or_else(|_| "unknown".to_string()),
+        };
+        let context = CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+        Entry {
+            timestamp: Utc::now(),
+            event: event.to_string(),
+            host,
+            session: context.session.clone(),
+            turn: context.turn,
+            model: context.model.clone(),
+            api_base: None,
+            tool_call_id: tool_call.map(|call| call.id.clone()),
+            function: tool_call.map(|call| call.function.name.clone()),
+            details: details.to_string(),
+            usage: None,
+            exit_code: None,
+            duration_ms: None,
+        }
+    }
+
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = host.to_string();
+        self
+    }
+
+    /// Where a completion came from, when it may not be the configured model.
+    pub fn endpoint(mut self, api_base: &str, model: &str) -> Self {
+        self.api_base = Some(api_base.to_string());
+        self.model = Some(model.to_string());
+        self
+    }
+
+    pub fn usage(mut self, usage: Option<CompletionUsage>) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Exit code and run time of a script.
+    pub fn script(mut self, exit_code: Option<i32>, elapsed: Option<Duration>) -> Self {
+        self.exit_code = exit_code;
+        self.duration_ms = elapsed.map(|elapsed| elapsed.as_millis() as u64);
+        self
+    }
+
+    /// Appends the entry to the log.
+    ///
+    /// The line is written with a single append, so concurrent ai_cli processes don't interleave.
+    pub fn write(&self) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path()?)?
+            .write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Records which session, turn and model the following events belong to.
+pub fn set_turn(session: &str, turn: usize, model: &str) {
+    let mut context = CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+    context.session = Some(session.to_string());
+    context.turn = Some(turn);
+    context.model = Some(model.to_string());
+}
+
+/// Location of the log, usually `~/.local/share/ai_cli/log.jsonl`.
+fn path() -> anyhow::Result<PathBuf> {
+    let dir = dirs::data_dir()
+        .ok_or(anyhow::anyhow!("No data directory on this system"))?
+        .join("ai_cli");
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join("log.jsonl"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One line per event
+    Text,
+    /// The log lines as they are stored
+    Json,
+    /// The CSV columns of older versions: timestamp, event, host, tool call id, function, details
+    Csv,
+}
+
+/// Which events `ai_cli log` prints.
+#[derive(clap::Args, Debug)]
+pub struct LogArgs {
+    /// Only events of this session
+    #[arg(long)]
+    session: Option<String>,
+    /// Only events of this type, like script_output or assistant
+    #[arg(long)]
+    event: Option<String>,
+    /// Only events that happened on this host
+    #[arg(long)]
+    host: Option<String>,
+    /// Only events since this date or time, like 2024-05-01 or 2024-05-01T12:00:00Z
+    #[arg(long)]
+    since: Option<String>,
+    /// Only the last N matching events
+    #[arg(short = 'n', long)]
+    last: Option<usize>,
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+/// Prints the events of the log matching `args`.
+pub fn run(args: LogArgs) -> anyhow::Result<()> {
+    let since = args.since.as_deref().map(parse_time).transpose()?;
+    let path = path()?;
+    let file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(&line).map_err(|e| {
+            anyhow::anyhow!(
+                "Invalid entry on line {} of {}: {}",
+                number + 1,
+                path.display(),
+                e
+            )
+        })?;
+        let matches = args
+            .session
+            .as_ref()
+            .is_none_or(|session| entry.session.as_ref() == Some(session))
+            && args
+                .event
+                .as_ref()
+                .is_none_or(|event| entry.event == *event)
+            && args.host.as_ref().is_none_or(|host| entry.host == *host)
+            && since.is_none_or(|since| entry.timestamp >= since);
+        if matches {
+            entries.push(entry);
+        }
+    }
+    if let Some(last) = args.last {
+        entries.drain(..entries.len().saturating_sub(last));
+    }
+
+    let mut out = io::stdout().lock();
+    match args.format {
+        Format::Text => {
+            for entry in &entries {
+                writeln!(out, "{}", describe(entry))?;
+            }
+        }
+        Format::Json => {
+            for entry in &entries {
+                writeln!(out, "{}", serde_json::to_string(entry)?)?;
+            }
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for entry in &entries {
+                writer.write_record([
+                    &entry.timestamp.to_rfc3339(),
+                    &entry.event,
+                    &entry.host,
+                    entry.tool_call_id.as_deref().unwrap_or_default(),
+                    entry.function.as_deref().unwrap_or_default(),
+                    &entry.details,
+                ])?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// A line for the terminal: time, session and turn, event, the extra fields and the first line
+/// of the details.
+fn describe(entry: &Entry) -> String {
+    let mut line = format!(
+        "{} {}",
+        entry
+            .timestamp
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S"),
+        entry.session.as_deref().unwrap_or("-")
+    );
+    if let Some(turn) = entry.turn {
+        line.push_str(&format!("#{}", turn));
+    }
+    line.push_str(&format!(" {} {}", entry.host, entry.event));
+    if let Some(usage) = &entry.usage {
+        line.push_str(&format!(
+            " tokens={}+{}",
+            usage.prompt_tokens, usage.completion_tokens
+        ));
+    }
+    if let Some(exit_code) = entry.exit_code {
+        line.push_str(&format!(" exit={}", exit_code));
+    }
+    if let Some(duration) = entry.duration_ms {
+        line.push_str(&format!(" {:.1}s", duration as f64 / 1000.0));
+    }
+    let details = entry.details.lines().next().unwrap_or_default();
+    if !details.is_empty() {
+        line.push_str(&format!(": {}", details));
+    }
+    line
+}
+
+/// Reads a date, taken as local midnight, or an RFC 3339 time.
+fn parse_time(s: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid time `{}`, use 2024-05-01 or 2024-05-01T12:00:00Z",
+            s
+        )
+    })?;
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|time| time.and_local_timezone(Local).earliest())
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or(anyhow::anyhow!("Invalid time `{}`", s))
+}
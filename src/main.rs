@@ -1,63 +1,97 @@
-use async_openai::{
-    Client,
-    config::OpenAIConfig,
-    types::{
-        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage, ChatCompletionTool,
-        ChatCompletionToolType, FunctionObject,
-    },
-};
-use chrono::prelude::*;
-use clap::{Parser, ValueHint};
-use serde::{Deserialize, Serialize};
-use std::fs::{self, OpenOptions};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, ValueHint};
 use std::io::{self, BufRead, Write};
-use std::path::Path;
-use std::process::Command;
-use sys_info::hostname;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+mod agent;
+mod attach;
+mod completion;
+mod config;
+mod confirm;
+mod extract;
+mod inventory;
+mod log;
+mod message;
+mod output;
+mod policy;
+mod prompt;
+mod repl;
+mod sampling;
+mod sandbox;
+mod session;
+mod shell;
+mod summary;
+mod text;
+mod tokens;
+
+use agent::{Agent, TurnEnd};
+use config::{Config, Profile};
+use inventory::Inventory;
+use output::{Event, OutputFormat, Status};
+use sampling::Sampling;
+use sandbox::{Backend, Sandbox};
+use session::{SessionsCommand, State};
+use tokens::Tokenizer;
 
-const CONVERSATION_FILE: &str = "/tmp/ai_conversation";
-const LOG_FILE: &str = "/tmp/ai_log.csv";
 const DEFAULT_API_BASE: &str = "http://ai3:8080/v1";
 const DEFAULT_API_KEY: &str = "empty";
-const SYSTEM_PROMPT: &str = r#"
-You are an AI assistant that can run terminal commands.
-To execute commands, format them like this:
-
-terminal_call:
-```
-your commands here
-```
-
-Important Guidelines:
-1. Commands will be executed as a POSIX-compliant shell script using /bin/sh
-2. Each execution starts fresh in the initial working directory
-3. You can use full shell syntax including &&, ||, ;, | etc.
-4. For file editing: Either use `sed` directly OR use `cat -n` to inspect with line numbers and then use `sed` to modify specific lines.
-5. Be cautious with destructive operations and always have rollbacks where possible.
-
-The script will be executed directly from memory when possible, or saved to /tmp/ai_cli_script.sh if needed.
-"#;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct State {
-    messages: Vec<ChatCompletionRequestMessage>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ToolMode {
+    /// Offer the terminal tool and fall back to text if the server rejects it
+    Auto,
+    /// Always send the terminal tool
+    Native,
+    /// Only parse terminal_call blocks from the response text
+    Text,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage saved conversations
+    Sessions {
+        #[command(subcommand)]
+        command: SessionsCommand,
+    },
+    /// Chat interactively, same as --interactive
+    Chat,
+    /// Print the event log, or export it as CSV
+    Log(log::LogArgs),
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Continue previous conversation
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Keep prompting for messages after each answer
     #[arg(short, long)]
-    continue_conversation: bool,
+    interactive: bool,
 
-    /// Confirm before executing commands
+    /// Continue the most recent conversation started from this directory
     #[arg(short, long)]
+    continue_conversation: bool,
+
+    /// Confirm before executing commands that aren't read-only or allowed
+    #[arg(short, long, overrides_with = "no_safe")]
     safe: bool,
 
+    /// Don't confirm commands, even if the profile turns on safe mode
+    #[arg(long, overrides_with = "safe")]
+    no_safe: bool,
+
     /// Keep looping until AI says "Fully Done Processing"
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_looping")]
     looping: bool,
 
+    /// Stop after one response, even if the profile turns on looping
+    #[arg(long, overrides_with = "looping")]
+    no_looping: bool,
+
     /// API base URL
     #[arg(short='b', long, default_value_t = DEFAULT_API_BASE.to_string(), value_hint = ValueHint::Url)]
     api_base: String,
@@ -70,266 +104,477 @@ struct Args {
     #[arg(short, long, default_value_t = String::from("qwen_coder"))]
     model: String,
 
+    /// Model to try when the previous ones keep failing, as MODEL, MODEL@API_BASE or @API_BASE
+    #[arg(long, value_name = "MODEL")]
+    fallback: Vec<String>,
+
+    /// Times a failed request is retried before falling back, with growing delays
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+
+    /// Seconds to wait for the server to start or continue answering before the request is
+    /// retried, 0 to wait forever
+    #[arg(long, default_value_t = 300)]
+    request_timeout: u64,
+
+    /// How the model requests commands: native tool calls, terminal_call blocks, or detect
+    #[arg(long, value_enum, default_value_t = ToolMode::Auto)]
+    tool_mode: ToolMode,
+
+    #[command(flatten)]
+    sampling: Sampling,
+
+    /// What to print on stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+
+    /// Same as --output-format jsonl
+    #[arg(long, conflicts_with = "output_format")]
+    json: bool,
+
+    /// Print the response only once it is complete instead of streaming tokens
+    #[arg(long)]
+    no_stream: bool,
+
+    /// Skip the remaining scripts of a response once one of them fails
+    #[arg(long)]
+    stop_on_error: bool,
+
+    /// Seconds before a script is killed, 0 to wait forever
+    #[arg(long, default_value_t = 600)]
+    timeout: u64,
+
+    /// Bytes of stdout and of stderr kept from each script, the middle is cut out, 0 keeps all
+    #[arg(long, default_value_t = 16384)]
+    max_output: usize,
+
+    /// Attach a file to the first message, may be a glob pattern and given several times
+    #[arg(short, long = "file", value_name = "PATH", value_hint = ValueHint::FilePath)]
+    files: Vec<String>,
+
+    /// Don't read piped input, for scripts that leave stdin open or use it for something else
+    #[arg(long)]
+    no_stdin: bool,
+
+    /// Bytes kept from each attached file or piped input, the middle is cut out, 0 keeps all
+    #[arg(long, default_value_t = 65536)]
+    max_attachment: usize,
+
+    /// Maximum number of model turns when looping
+    #[arg(long, default_value_t = 20)]
+    max_iterations: usize,
+
+    /// Tokens the conversation may use before old script outputs are trimmed, 0 to disable
+    #[arg(long, default_value_t = 32768)]
+    context_budget: usize,
+
+    /// Tokens the conversation may reach before older messages are summarized, 0 to disable
+    #[arg(long, default_value_t = 24576)]
+    summarize_at: usize,
+
+    /// How to count tokens for the context budget
+    #[arg(long, value_enum, default_value_t = Tokenizer::Auto)]
+    tokenizer: Tokenizer,
+
+    /// Command that runs without confirmation in safe mode, matched by its leading words
+    #[arg(long, value_name = "COMMAND")]
+    allow: Vec<String>,
+
+    /// Command that is never run, matched by its leading words
+    #[arg(long, value_name = "COMMAND")]
+    deny: Vec<String>,
+
+    /// Run scripts on this SSH destination instead of locally, like user@host
+    #[arg(short, long, value_hint = ValueHint::Hostname)]
+    target: Option<String>,
+
+    /// Run scripts on every host of these inventory groups at once, separated by commas
+    #[arg(long, value_name = "GROUP")]
+    hosts: Option<String>,
+
+    /// Inventory file with the host groups, `inventory.toml` in the config directory by default
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    inventory: Option<PathBuf>,
+
+    /// Where scripts run
+    #[arg(long, value_enum, default_value_t = Sandbox::Host)]
+    sandbox: Sandbox,
+
+    /// Image for the container sandbox
+    #[arg(long, default_value_t = sandbox::DEFAULT_IMAGE.to_string())]
+    sandbox_image: String,
+
+    /// Writable directory of the sandbox, a new one per session by default
+    #[arg(long, value_hint = ValueHint::DirPath)]
+    sandbox_dir: Option<PathBuf>,
+
+    /// File whose contents are added to the system prompt of new conversations
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    system_prompt: Option<PathBuf>,
+
+    /// Name of the conversation to continue or start
+    #[arg(long)]
+    session: Option<String>,
+
+    /// Profile from the config file to take defaults from
+    #[arg(short, long)]
+    profile: Option<String>,
+
     message: Vec<String>,
 }
 
+impl Args {
+    /// Fills in everything that wasn't given on the command line from the profile and environment.
+    ///
+    /// The API key is taken from `AI_CLI_API_KEY`, then the profile, then `OPENAI_API_KEY`.
+    fn apply_profile(&mut self, profile: Profile, matches: &ArgMatches) {
+        let from_cli = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+        if !from_cli("api_base")
+            && let Some(api_base) = profile.api_base
+        {
+            self.api_base = api_base;
+        }
+        if !from_cli("api_key")
+            && let Some(api_key) = std::env::var("AI_CLI_API_KEY")
+                .ok()
+                .or(profile.api_key)
+                .or_else(|| std::env::var("OPENAI_API_KEY").ok())
+        {
+            self.api_key = api_key;
+        }
+        if !from_cli("model")
+            && let Some(model) = profile.model
+        {
+            self.model = model;
+        }
+        self.sampling = std::mem::take(&mut self.sampling).or(Sampling {
+            temperature: profile.temperature,
+            top_p: profile.top_p,
+            max_tokens: profile.max_tokens,
+            stop: profile.stop,
+            seed: profile.seed,
+            frequency_penalty: profile.frequency_penalty,
+            presence_penalty: profile.presence_penalty,
+        });
+        if !from_cli("retries")
+            && let Some(retries) = profile.retries
+        {
+            self.retries = retries;
+        }
+        if !from_cli("request_timeout")
+            && let Some(request_timeout) = profile.request_timeout
+        {
+            self.request_timeout = request_timeout;
+        }
+        if !from_cli("max_output")
+            && let Some(max_output) = profile.max_output
+        {
+            self.max_output = max_output;
+        }
+        if !from_cli("max_attachment")
+            && let Some(max_attachment) = profile.max_attachment
+        {
+            self.max_attachment = max_attachment;
+        }
+        if !from_cli("context_budget")
+            && let Some(context_budget) = profile.context_budget
+        {
+            self.context_budget = context_budget;
+        }
+        if !from_cli("summarize_at")
+            && let Some(summarize_at) = profile.summarize_at
+        {
+            self.summarize_at = summarize_at;
+        }
+        if !from_cli("tokenizer")
+            && let Some(tokenizer) = profile.tokenizer
+        {
+            self.tokenizer = tokenizer;
+        }
+        if !from_cli("sandbox")
+            && let Some(sandbox) = profile.sandbox
+        {
+            self.sandbox = sandbox;
+        }
+        if !from_cli("sandbox_image")
+            && let Some(sandbox_image) = profile.sandbox_image
+        {
+            self.sandbox_image = sandbox_image;
+        }
+        if self.sandbox_dir.is_none() {
+            self.sandbox_dir = profile.sandbox_dir;
+        }
+        if self.inventory.is_none() {
+            self.inventory = profile.inventory;
+        }
+        if self.system_prompt.is_none() {
+            self.system_prompt = profile.system_prompt;
+        }
+        self.fallback.extend(profile.fallback);
+        self.allow.extend(profile.allow);
+        self.deny.extend(profile.deny);
+        if !from_cli("safe")
+            && !from_cli("no_safe")
+            && let Some(safe) = profile.safe
+        {
+            self.safe = safe;
+        }
+        if !from_cli("looping")
+            && !from_cli("no_looping")
+            && let Some(looping) = profile.looping
+        {
+            self.looping = looping;
+        }
+    }
+}
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let cli_args = Args::parse();
-    let mut user_message = if cli_args.message.is_empty() {
-        eprint!("Message: ");
-        io::stdout().flush()?;
-        let mut buffer = String::new();
-        io::stdin().lock().read_line(&mut buffer)?;
-        buffer.trim().to_owned()
+async fn main() -> anyhow::Result<ExitCode> {
+    let matches = Args::command().get_matches();
+    let mut cli_args = Args::from_arg_matches(&matches)?;
+    let profile = Config::load()?.profile(cli_args.profile.as_deref())?;
+    cli_args.apply_profile(profile, &matches);
+
+    match cli_args.command.take() {
+        Some(Command::Sessions { command }) => {
+            return session::run(command).map(|_| ExitCode::SUCCESS);
+        }
+        Some(Command::Log(args)) => return log::run(args).map(|_| ExitCode::SUCCESS),
+        Some(Command::Chat) => cli_args.interactive = true,
+        None => {}
+    }
+
+    let interactive = cli_args.interactive;
+    if cli_args.json {
+        cli_args.output_format = OutputFormat::Jsonl;
+    }
+    if cli_args.output_format == OutputFormat::Jsonl && interactive {
+        anyhow::bail!("--output-format jsonl can't be combined with interactive chat");
+    }
+    output::set_format(cli_args.output_format);
+    let mut piped = if cli_args.no_stdin {
+        None
     } else {
-        cli_args.message.join(" ")
+        attach::piped_stdin()?
     };
-
-    if cli_args.looping {
-        user_message.push_str("\n\nIMPORTANT: To keep processing, provide terminal commands when needed. When fully done, say \"Fully Done Processing\".");
+    let first_message = if !cli_args.message.is_empty() {
+        Some(cli_args.message.join(" "))
+    } else if interactive {
+        None
+    } else if let Some(input) = piped.take() {
+        // Without a message on the command line, what is piped in is the message.
+        Some(String::from_utf8_lossy(&input).trim().to_string())
+    } else {
+        Some(read_user_message()?)
+    };
+    let mut context = Vec::new();
+    if let Some(input) = &piped {
+        context.push(attach::stdin(input, cli_args.max_attachment));
+    }
+    if !cli_args.files.is_empty() {
+        let files = attach::expand(&cli_args.files)?;
+        context.push(attach::files(&files, cli_args.max_attachment)?);
     }
-    user_message.push_str("If you want to call a script, use terminal_call:\\n```");
 
-    let mut state = if cli_args.continue_conversation && Path::new(CONVERSATION_FILE).exists() {
-        let s = fs::read_to_string(CONVERSATION_FILE)?;
-        serde_json::from_str(&s)?
+    let cwd = std::env::current_dir()?;
+    let session_name = match &cli_args.session {
+        Some(name) => name.clone(),
+        None if cli_args.continue_conversation => {
+            session::latest_for(&cwd)?.unwrap_or_else(session::new_name)
+        }
+        None => session::new_name(),
+    };
+    let mut saved = if session::exists(&session_name)? {
+        Some(session::load(&session_name)?)
     } else {
-        State {
-            messages: vec![ChatCompletionRequestMessage::System(
-                ChatCompletionRequestSystemMessage {
-                    content: async_openai::types::ChatCompletionRequestSystemMessageContent::Text(
-                        SYSTEM_PROMPT.trim().to_owned(),
-                    ),
-                    name: None,
-                },
-            )],
+        None
+    };
+    // A continued conversation keeps working on the machines it was set up on.
+    if cli_args.target.is_none()
+        && cli_args.hosts.is_none()
+        && let Some(saved) = &saved
+    {
+        cli_args.target = saved.target.clone();
+        cli_args.hosts = saved.hosts.clone();
+    }
+    // It also keeps its sandbox and sampling like before, the profile only fills in what
+    // wasn't saved.
+    if let Some(saved) = &mut saved {
+        let from_cli = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+        if !from_cli("sandbox")
+            && let Some(sandbox) = saved.sandbox
+        {
+            cli_args.sandbox = sandbox;
         }
+        if !from_cli("sandbox_image")
+            && let Some(sandbox_image) = &saved.sandbox_image
+        {
+            cli_args.sandbox_image = sandbox_image.clone();
+        }
+        // The saved directory and environment only make sense where they were captured.
+        if saved
+            .sandbox
+            .is_some_and(|sandbox| sandbox != cli_args.sandbox)
+        {
+            eprintln!("The session ran in another sandbox, its shell state is not restored.");
+            saved.shell = None;
+            saved.host_shells.clear();
+        }
+        cli_args.sampling = Sampling::from_arg_matches(&matches)?
+            .or(saved.sampling.clone())
+            .or(cli_args.sampling);
+    }
+    let targets = match &cli_args.hosts {
+        Some(_) if cli_args.target.is_some() => {
+            anyhow::bail!("--hosts can't be combined with --target")
+        }
+        Some(groups) => Inventory::load(cli_args.inventory.as_deref())?
+            .hosts(groups)?
+            .into_iter()
+            .map(Some)
+            .collect(),
+        None => vec![cli_args.target.clone()],
     };
-
-    state.messages.push(ChatCompletionRequestMessage::User(
-        user_message.clone().into(),
-    ));
-
-    log_event("user", None, &user_message)?;
-
-    // Create async-openai client with config
-    let ai_config = OpenAIConfig::new()
-        .with_api_base(&cli_args.api_base)
-        .with_api_key(&cli_args.api_key);
-    let ai_client = Client::with_config(ai_config);
-
-    // Define the terminal tool for API requests
-    let _terminal_tool = ChatCompletionTool {
-        r#type: ChatCompletionToolType::Function,
-        function: FunctionObject {
-            name: "terminal".to_string(),
-            description: Some("Run a terminal command and get the output. Maintains current working directory across calls.".to_string()),
-            parameters: Some(serde_json::json!({
-                "type": "object",
-                "properties": {
-                    "command": {
-                        "type": "string",
-                        "description": "The command to execute"
-                    }
-                },
-                "required": ["command"]
-            })),
-            strict: None,
-        },
+    let backends = targets
+        .iter()
+        .map(|target| {
+            Backend::new(
+                cli_args.sandbox,
+                &cli_args.sandbox_image,
+                cli_args.sandbox_dir.as_deref(),
+                target.as_deref(),
+                &session_name,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let state = match saved {
+        Some(saved) => saved,
+        None => State::new(
+            &prompt::system(&backends, cli_args.system_prompt.as_deref())?,
+            cwd,
+        ),
     };
 
-    let messages = state.messages.clone();
-    let request = async_openai::types::CreateChatCompletionRequest {
-        model: cli_args.model.clone(),
-        messages,
-        ..Default::default()
+    let interrupt = Interrupt::install();
+    let mut agent = Agent::new(cli_args, session_name, state, backends, interrupt.clone())?;
+    if !context.is_empty() {
+        agent.context = Some(context.join("\n\n"));
+    }
+
+    let result = converse(&mut agent, first_message, interactive, &interrupt).await;
+    let status = match &result {
+        Ok(TurnEnd::Finished) => Status::Finished,
+        Ok(TurnEnd::WaitingForUser | TurnEnd::OutOfIterations) => Status::Incomplete,
+        Ok(TurnEnd::Canceled) => Status::Canceled,
+        Err(_) => Status::Error,
     };
+    output::emit(&Event::Finished {
+        status,
+        session: &agent.session_name,
+        prompt_tokens: agent.prompt_tokens,
+        completion_tokens: agent.completion_tokens,
+        error: result.as_ref().err().map(|e| e.to_string()),
+    })?;
+    agent.save()?;
+    result?;
+    Ok(ExitCode::from(status.exit_code()))
+}
 
-    let response = ai_client.chat().create(request).await?;
-    let message = response
-        .choices
-        .first()
-        .ok_or(anyhow::anyhow!("No choices returned"))?
-        .message
-        .clone();
-    let content = message.content.as_deref().unwrap_or_default();
-
-    // Print assistant message
-    println!("{}", content);
-    log_event("assistant", None, content)?;
-
-    // Check for terminal call
-    if let Some(script) = extract_terminal_call(content) {
-        // Calculate context length
-        let context_len = estimate_context_length(&state.messages);
-        println!("Current context length: {} tokens", context_len);
-
-        if cli_args.safe {
-            print!("Execute script? [Y/n]: ");
-            io::stdout().flush()?;
-            let mut input = String::new();
-            io::stdin().read_line(&mut input)?;
-            if input.trim().to_lowercase() == "n" {
-                log_event("script_canceled", None, "User canceled")?;
-                return Ok(());
+/// Sends the first message and, when interactive, the ones typed after it.
+async fn converse(
+    agent: &mut Agent,
+    first_message: Option<String>,
+    interactive: bool,
+    interrupt: &Interrupt,
+) -> anyhow::Result<TurnEnd> {
+    let mut end = TurnEnd::Finished;
+    if let Some(mut message) = first_message {
+        // While looping, a model that stops to ask something gets its answer from stdin.
+        loop {
+            end = agent.send(message).await?;
+            if end != TurnEnd::WaitingForUser || interactive {
+                break;
+            }
+            message = read_user_message()?;
+            if message.is_empty() || interrupt.is_set() {
+                break;
             }
         }
-
-        let result = run_script(&script)?;
-        println!("{}", result);
-        log_event("script_output", None, &result)?;
-
-        // Append output to conversation
-        state.messages.push(ChatCompletionRequestMessage::Assistant(
-            async_openai::types::ChatCompletionRequestAssistantMessage {
-                content: Some(
-                    async_openai::types::ChatCompletionRequestAssistantMessageContent::Text(
-                        format!(
-                            "Script executed:\n```\n{}\n```\nOutput:\n{}",
-                            script, result
-                        ),
-                    ),
-                ),
-                name: None,
-                tool_calls: None,
-                #[allow(deprecated)]
-                function_call: None,
-                audio: None,
-                refusal: None,
-            },
-        ));
     }
+    if interactive {
+        repl::run(agent).await?;
+        end = TurnEnd::Finished;
+    }
+    Ok(end)
+}
 
-    save_state(&state)?;
+/// Prompts for a message on stdin.
+fn read_user_message() -> anyhow::Result<String> {
+    eprint!("Message: ");
+    io::stdout().flush()?;
+    let mut buffer = String::new();
+    io::stdin().lock().read_line(&mut buffer)?;
+    Ok(buffer.trim().to_owned())
+}
 
-    if cli_args.looping {
-        let content_lower = content.to_lowercase();
-        if content_lower.contains("fully done processing") {
-            return Ok(());
-        }
+/// Ctrl-C handling for the agent loop.
+///
+/// The first Ctrl-C asks the loop to stop at the next opportunity, a second one exits immediately.
+#[derive(Clone)]
+struct Interrupt {
+    flag: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
 
-        // Recurse with empty message to continue
-        let new_args = Args {
-            message: vec![],
-            continue_conversation: true,
-            safe: cli_args.safe,
-            looping: true,
-            api_base: cli_args.api_base.clone(),
-            api_key: cli_args.api_key.clone(),
-            model: cli_args.model.clone(),
+impl Interrupt {
+    fn install() -> Self {
+        let interrupt = Interrupt {
+            flag: Arc::new(AtomicBool::new(false)),
+            notify: Arc::new(Notify::new()),
         };
-        return main_with_args(new_args).await;
+        let handler = interrupt.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if handler.flag.swap(true, Ordering::SeqCst) {
+                    std::process::exit(130);
+                }
+                eprintln!(
+                    "\nInterrupt received, stopping after the current step. Press Ctrl-C again to quit."
+                );
+                handler.notify.notify_waiters();
+            }
+        });
+        interrupt
     }
 
-    Ok(())
-}
-
-async fn main_with_args(cli_args: Args) -> anyhow::Result<()> {
-    let mut user_message = if cli_args.message.is_empty() {
-        eprint!("Message: ");
-        io::stdout().flush()?;
-        let mut buffer = String::new();
-        io::stdin().lock().read_line(&mut buffer)?;
-        buffer.trim().to_owned()
-    } else {
-        cli_args.message.join(" ")
-    };
-
-    if cli_args.looping {
-        user_message.push_str("\n\nIMPORTANT: To keep processing, provide terminal commands when needed. When fully done, say \"Fully Done Processing\".");
+    fn is_set(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
     }
-    user_message.push_str("If you want to call a script, use terminal_call:\\n```");
-    Ok(())
-}
 
-fn extract_terminal_call(content: &str) -> Option<String> {
-    let pattern = "terminal_call:\n```\n";
-    if let Some(start) = content.find(pattern) {
-        let remaining = &content[start + pattern.len()..];
-        if let Some(end) = remaining.find("\n```") {
-            return Some(remaining[..end].to_string());
-        }
+    /// Stops the loop like Ctrl-C does, for an interrupt that reached a script instead.
+    fn set(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
     }
-    None
-}
 
-fn estimate_context_length(messages: &[ChatCompletionRequestMessage]) -> usize {
-    let total_words: usize = messages
-        .iter()
-        .map(|msg| match msg {
-            ChatCompletionRequestMessage::System(s) => match &s.content {
-                async_openai::types::ChatCompletionRequestSystemMessageContent::Text(t) => {
-                    t.split_whitespace().count()
-                }
-                _ => 0,
-            },
-            ChatCompletionRequestMessage::User(u) => match &u.content {
-                async_openai::types::ChatCompletionRequestUserMessageContent::Text(t) => {
-                    t.split_whitespace().count()
-                }
-                _ => 0,
-            },
-            ChatCompletionRequestMessage::Assistant(a) => {
-                a.content.as_ref().map_or(0, |c| match c {
-                    async_openai::types::ChatCompletionRequestAssistantMessageContent::Text(t) => {
-                        t.split_whitespace().count()
-                    }
-                    _ => 0,
-                })
-            }
-            _ => 0,
-        })
-        .sum();
-    (total_words as f64 * 1.2).round() as usize
-}
+    /// Clears a handled interrupt, so the next one stops the loop again instead of exiting.
+    fn reset(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
 
-fn run_script(script: &str) -> anyhow::Result<String> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(script)
-        .current_dir(std::env::current_dir()?)
-        .output()?;
-
-    let result = String::from_utf8(output.stdout)?;
-    if !output.status.success() {
-        let stderr = String::from_utf8(output.stderr)?;
-        return Ok(format!("Error:\n{}\nOutput:\n{}", stderr, result));
+    async fn wait(&self) {
+        let notified = self.notify.notified();
+        if self.is_set() {
+            return;
+        }
+        notified.await;
     }
-    Ok(result)
 }
 
-#[allow(unused_variables)]
 fn log_event(
     event_type: &str,
     tool_call: Option<&async_openai::types::ChatCompletionMessageToolCall>,
     details: &str,
 ) -> anyhow::Result<()> {
-    let file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(LOG_FILE)?;
-
-    let host = hostname().unwrap_or_else(|_| "unknown".to_string());
-    let timestamp = Utc::now().to_rfc3339();
-
-    let (function, id) = match tool_call {
-        Some(call) => (call.function.name.clone(), call.id.clone()),
-        None => ("".to_string(), "".to_string()),
-    };
-
-    let mut wtr = csv::WriterBuilder::new()
-        .has_headers(!Path::new(LOG_FILE).exists())
-        .from_writer(file);
-
-    wtr.write_record(&[&timestamp, event_type, &host, &id, &function, details])?;
-
-    wtr.flush()?;
-    Ok(())
-}
-
-fn save_state(state: &State) -> anyhow::Result<()> {
-    let json = serde_json::to_string(state)?;
-    fs::write(CONVERSATION_FILE, json)?;
-    Ok(())
+    log::Entry::new(event_type, tool_call, details).write()
 }
@@ -0,0 +1,211 @@
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestAssistantMessageContentPart,
+    ChatCompletionRequestDeveloperMessageContent, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestSystemMessageContentPart,
+    ChatCompletionRequestToolMessage, ChatCompletionRequestToolMessageContent,
+    ChatCompletionRequestToolMessageContentPart, ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart,
+};
+
+/// Name of the role a message is sent as.
+pub fn role(message: &ChatCompletionRequestMessage) -> &'static str {
+    match message {
+        ChatCompletionRequestMessage::System(_) => "system",
+        ChatCompletionRequestMessage::Developer(_) => "developer",
+        ChatCompletionRequestMessage::User(_) => "user",
+        ChatCompletionRequestMessage::Assistant(_) => "assistant",
+        ChatCompletionRequestMessage::Tool(_) => "tool",
+        ChatCompletionRequestMessage::Function(_) => "function",
+    }
+}
+
+/// All the text the model sees in a message, including tool call names and arguments.
+///
+/// Images and audio are left out, see [`non_text_parts`].
+pub fn text(message: &ChatCompletionRequestMessage) -> String {
+    let parts: Vec<String> = match message {
+        ChatCompletionRequestMessage::System(s) => match &s.content {
+            ChatCompletionRequestSystemMessageContent::Text(t) => vec![t.clone()],
+            ChatCompletionRequestSystemMessageContent::Array(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    ChatCompletionRequestSystemMessageContentPart::Text(t) => t.text.clone(),
+                })
+                .collect(),
+        },
+        ChatCompletionRequestMessage::Developer(d) => match &d.content {
+            ChatCompletionRequestDeveloperMessageContent::Text(t) => vec![t.clone()],
+            ChatCompletionRequestDeveloperMessageContent::Array(parts) => {
+                parts.iter().map(|part| part.text.clone()).collect()
+            }
+        },
+        ChatCompletionRequestMessage::User(u) => match &u.content {
+            ChatCompletionRequestUserMessageContent::Text(t) => vec![t.clone()],
+            ChatCompletionRequestUserMessageContent::Array(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ChatCompletionRequestUserMessageContentPart::Text(t) => Some(t.text.clone()),
+                    _ => None,
+                })
+                .collect(),
+        },
+        ChatCompletionRequestMessage::Assistant(a) => {
+            let mut parts = match &a.content {
+                Some(ChatCompletionRequestAssistantMessageContent::Text(t)) => vec![t.clone()],
+                Some(ChatCompletionRequestAssistantMessageContent::Array(parts)) => parts
+                    .iter()
+                    .map(|part| match part {
+                        ChatCompletionRequestAssistantMessageContentPart::Text(t) => t.text.clone(),
+                        ChatCompletionRequestAssistantMessageContentPart::Refusal(r) => {
+                            r.refusal.clone()
+                        }
+                    })
+                    .collect(),
+                None => vec![],
+            };
+            parts.extend(a.refusal.clone());
+            for call in a.tool_calls.iter().flatten() {
+                parts.push(format!(
+                    "{}: {}",
+                    call.function.name, call.function.arguments
+                ));
+            }
+            parts
+        }
+        ChatCompletionRequestMessage::Tool(t) => match &t.content {
+            ChatCompletionRequestToolMessageContent::Text(t) => vec![t.clone()],
+            ChatCompletionRequestToolMessageContent::Array(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    ChatCompletionRequestToolMessageContentPart::Text(t) => t.text.clone(),
+                })
+                .collect(),
+        },
+        ChatCompletionRequestMessage::Function(f) => {
+            vec![f.name.clone(), f.content.clone().unwrap_or_default()]
+        }
+    };
+    parts.join("\n")
+}
+
+/// Number of image and audio parts in a message.
+pub fn non_text_parts(message: &ChatCompletionRequestMessage) -> usize {
+    match message {
+        ChatCompletionRequestMessage::User(u) => match &u.content {
+            ChatCompletionRequestUserMessageContent::Array(parts) => parts
+                .iter()
+                .filter(|part| {
+                    !matches!(part, ChatCompletionRequestUserMessageContentPart::Text(_))
+                })
+                .count(),
+            ChatCompletionRequestUserMessageContent::Text(_) => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// Starts every script result message, see [`script_output`].
+const SCRIPT_OUTPUT_PREFIX: &str = "Script executed:\n";
+/// Starts the message about terminal_call blocks that couldn't be read, see [`malformed_calls`].
+const MALFORMED_CALLS_PREFIX: &str = "Some terminal_call blocks could not be run:\n";
+
+/// An assistant message with optional text and tool calls.
+pub fn assistant(
+    content: Option<String>,
+    tool_calls: Option<Vec<ChatCompletionMessageToolCall>>,
+) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+        content: content.map(ChatCompletionRequestAssistantMessageContent::Text),
+        name: None,
+        tool_calls,
+        #[allow(deprecated)]
+        function_call: None,
+        audio: None,
+        refusal: None,
+    })
+}
+
+/// The results of the terminal_call blocks of a response, as they are added to the conversation.
+///
+/// They are sent as a user message, following the assistant message that asked for them.
+pub fn script_output(runs: &[(String, String)]) -> ChatCompletionRequestMessage {
+    let sections: Vec<String> = runs
+        .iter()
+        .map(|(script, result)| {
+            format!(
+                "{}```\n{}\n```\nOutput:\n{}",
+                SCRIPT_OUTPUT_PREFIX, script, result
+            )
+        })
+        .collect();
+    ChatCompletionRequestMessage::User(sections.join("\n\n").into())
+}
+
+/// Tells the model which of its terminal_call blocks couldn't be read.
+pub fn malformed_calls(errors: &[String]) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage::User(
+        format!(
+            "{}{}\nWrite them as `terminal_call:` followed by a ``` fenced block.",
+            MALFORMED_CALLS_PREFIX,
+            errors.join("\n")
+        )
+        .into(),
+    )
+}
+
+/// Whether the message holds the output of a script, from a tool call or a terminal_call block.
+///
+/// Sessions saved by older versions have terminal_call output as assistant messages.
+pub fn is_script_output(message: &ChatCompletionRequestMessage) -> bool {
+    match message {
+        ChatCompletionRequestMessage::Tool(_) => true,
+        ChatCompletionRequestMessage::User(u) => matches!(
+            &u.content,
+            ChatCompletionRequestUserMessageContent::Text(t) if t.starts_with(SCRIPT_OUTPUT_PREFIX)
+        ),
+        ChatCompletionRequestMessage::Assistant(a) => matches!(
+            &a.content,
+            Some(ChatCompletionRequestAssistantMessageContent::Text(t)) if t.starts_with(SCRIPT_OUTPUT_PREFIX)
+        ),
+        _ => false,
+    }
+}
+
+/// Whether the message was written by the user, rather than sent for them with script output.
+pub fn is_prompt(message: &ChatCompletionRequestMessage) -> bool {
+    match message {
+        ChatCompletionRequestMessage::User(u) => !matches!(
+            &u.content,
+            ChatCompletionRequestUserMessageContent::Text(t)
+                if t.starts_with(SCRIPT_OUTPUT_PREFIX) || t.starts_with(MALFORMED_CALLS_PREFIX)
+        ),
+        _ => false,
+    }
+}
+
+/// Replaces the content of a user, assistant or tool message with plain text.
+///
+/// Tool calls are kept so tool results still have something to answer.
+pub fn replace_text(message: &mut ChatCompletionRequestMessage, text: String) {
+    match message {
+        ChatCompletionRequestMessage::User(u) => {
+            u.content = ChatCompletionRequestUserMessageContent::Text(text)
+        }
+        ChatCompletionRequestMessage::Assistant(a) => {
+            a.content = Some(ChatCompletionRequestAssistantMessageContent::Text(text))
+        }
+        ChatCompletionRequestMessage::Tool(t) => {
+            t.content = ChatCompletionRequestToolMessageContent::Text(text)
+        }
+        _ => {}
+    }
+}
+
+/// The result of a tool call.
+pub fn tool(tool_call_id: &str, result: String) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessage {
+        content: ChatCompletionRequestToolMessageContent::Text(result),
+        tool_call_id: tool_call_id.to_string(),
+    })
+}
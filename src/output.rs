@@ -0,0 +1,108 @@
+//! What goes to stdout: the conversation for people, or JSON Lines events for scripts.
+//!
+//! With `--output-format jsonl` every line on stdout is one [`Event`], everything meant for
+//! people goes to stderr.
+
+use async_openai::types::CompletionUsage;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// The assistant's text and live script output
+    Text,
+    /// One JSON event per line
+    Jsonl,
+}
+
+/// Something that happened, as printed in the jsonl format.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A response of the model.
+    Assistant {
+        content: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        usage: Option<&'a CompletionUsage>,
+    },
+    /// A script the model wants to run, before it is confirmed or run.
+    Script {
+        script: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool_call_id: Option<&'a str>,
+    },
+    /// What running a script gave, as it is sent to the model.
+    Result {
+        script: &'a str,
+        output: &'a str,
+        success: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exit_code: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        duration_ms: Option<u64>,
+    },
+    /// The last event, see [`Status`].
+    Finished {
+        status: Status,
+        session: &'a str,
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/// How the run ended, which also decides the exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The model is done, exit code 0.
+    Finished,
+    /// Something went wrong, like the API being unreachable, exit code 1.
+    Error,
+    /// Out of iterations, or the model asked for input that never came, exit code 2.
+    Incomplete,
+    /// Interrupted or canceled by the user, exit code 130 like for Ctrl-C.
+    Canceled,
+}
+
+impl Status {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Status::Finished => 0,
+            Status::Error => 1,
+            Status::Incomplete => 2,
+            Status::Canceled => 130,
+        }
+    }
+}
+
+pub fn set_format(format: OutputFormat) {
+    let _ = FORMAT.set(format);
+}
+
+/// Whether stdout is reserved for JSON events.
+pub fn is_json() -> bool {
+    FORMAT.get() == Some(&OutputFormat::Jsonl)
+}
+
+/// Prints the event in the jsonl format, does nothing otherwise.
+pub fn emit(event: &Event) -> anyhow::Result<()> {
+    if !is_json() {
+        return Ok(());
+    }
+    let mut out = io::stdout().lock();
+    writeln!(out, "{}", serde_json::to_string(event)?)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Milliseconds, as reported in events.
+pub fn millis(duration: Option<Duration>) -> Option<u64> {
+    duration.map(|duration| duration.as_millis() as u64)
+}
@@ -0,0 +1,105 @@
+//! The system prompt of new conversations: the built-in instructions, where commands run and
+//! the user's own additions.
+
+use chrono::Local;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::sandbox::Backend;
+
+const SYSTEM_PROMPT: &str = r#"
+You are an AI assistant that can run terminal commands.
+To execute commands, format them like this:
+
+terminal_call:
+```
+your commands here
+```
+
+Important Guidelines:
+1. Commands will be executed as a POSIX-compliant shell script using /bin/sh
+2. All executions share one shell session, so the working directory and exported variables persist between calls
+3. You can use full shell syntax including &&, ||, ;, | etc.
+4. For file editing: Either use `sed` directly OR use `cat -n` to inspect with line numbers and then use `sed` to modify specific lines.
+5. Be cautious with destructive operations and always have rollbacks where possible.
+
+The script is saved to a temporary file and sourced into the session.
+"#;
+/// Project prompt looked up in the starting directory and its parents.
+const PROJECT_FILE: &str = ".ai_cli.md";
+
+/// Location of the user's prompt, usually `~/.config/ai_cli/system_prompt.md`.
+fn user_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("ai_cli").join("system_prompt.md"))
+}
+
+/// The system prompt for a new conversation, which tells the model where its commands run.
+///
+/// The user's prompt, the project's `.ai_cli.md` and `custom` (from `--system-prompt`) are
+/// appended in that order when they exist, with their template variables filled in.
+pub fn system(backends: &[Backend], custom: Option<&Path>) -> anyhow::Result<String> {
+    let environment = match backends {
+        [backend] => backend.describe(),
+        _ => format!(
+            "Commands run on each of these hosts at once over SSH: {}. The output of every host is reported separately.",
+            backends
+                .iter()
+                .filter_map(Backend::target)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut parts = vec![SYSTEM_PROMPT.trim().to_string(), environment];
+
+    let cwd = std::env::current_dir()?;
+    let mut files: Vec<PathBuf> = user_path()
+        .filter(|path| path.is_file())
+        .into_iter()
+        .collect();
+    files.extend(
+        cwd.ancestors()
+            .map(|dir| dir.join(PROJECT_FILE))
+            .find(|path| path.is_file()),
+    );
+    files.extend(custom.map(Path::to_path_buf));
+    for path in files {
+        let text = fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Can't read {}: {}", path.display(), e))?;
+        eprintln!("Using the system prompt in {}", path.display());
+        parts.push(fill(text.trim(), &cwd));
+    }
+    Ok(parts.join("\n\n"))
+}
+
+/// Replaces `{hostname}`, `{os}`, `{cwd}`, `{date}` and `{user}` with what they stand for on
+/// this machine. Other braces are left alone.
+fn fill(text: &str, cwd: &Path) -> String {
+    let os = sys_info::linux_os_release()
+        .ok()
+        .and_then(|release| release.pretty_name)
+        .or_else(|| {
+            Some(format!(
+                "{} {}",
+                sys_info::os_type().ok()?,
+                sys_info::os_release().ok()?
+            ))
+        })
+        .unwrap_or_else(|| std::env::consts::OS.to_string());
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    [
+        (
+            "{hostname}",
+            sys_info::hostname().unwrap_or_else(|_| "unknown".to_string()),
+        ),
+        ("{os}", os),
+        ("{cwd}", cwd.display().to_string()),
+        ("{date}", Local::now().format("%Y-%m-%d").to_string()),
+        ("{user}", user),
+    ]
+    .iter()
+    .fold(text.to_string(), |text, (variable, value)| {
+        text.replace(variable, value)
+    })
+}
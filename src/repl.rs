@@ -0,0 +1,157 @@
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::agent::Agent;
+use crate::session;
+
+const HELP: &str = r#"Commands:
+  /model [NAME]     show or change the model
+  /safe [on|off]    show or change confirmation before running scripts
+  /save [NAME]      save the conversation, optionally under a new name
+  /undo             remove the last message you sent and everything after it
+  /reset            start a new conversation
+  /help             show this help
+  /exit             leave (Ctrl-D works too)
+End a line with \ to continue the message on the next line."#;
+
+/// Where typed messages are remembered between runs, usually `~/.local/share/ai_cli/history`.
+fn history_path() -> Option<PathBuf> {
+    let dir = dirs::data_dir()?.join("ai_cli");
+    fs::create_dir_all(&dir).ok()?;
+    Some(dir.join("history"))
+}
+
+/// Reads messages until the user leaves, sending each to the agent.
+pub async fn run(agent: &mut Agent) -> anyhow::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(history) = &history {
+        // Missing on the first run.
+        let _ = editor.load_history(history);
+    }
+    eprintln!("Session {}. Type /help for commands.", agent.session_name);
+
+    loop {
+        let input = match read_message(&mut editor) {
+            Ok(input) => input,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(input)?;
+        if let Some(history) = &history {
+            editor.save_history(history)?;
+        }
+
+        if let Some(command) = input.strip_prefix('/') {
+            match run_command(agent, command) {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+            }
+        }
+
+        // A failed request or shell shouldn't end the chat, the message can be sent again.
+        if let Err(e) = agent.send(input.to_string()).await {
+            eprintln!("Error: {:#}", e);
+            // Nothing answered it, sending it again would otherwise leave it in twice.
+            if agent
+                .state
+                .messages
+                .last()
+                .is_some_and(crate::message::is_prompt)
+            {
+                agent.state.messages.pop();
+            }
+            agent.save()?;
+        }
+    }
+
+    agent.save()
+}
+
+/// Reads one message, joining lines that end with a backslash.
+fn read_message(editor: &mut DefaultEditor) -> Result<String, ReadlineError> {
+    let mut message = String::new();
+    let mut prompt = "> ";
+    loop {
+        let line = editor.readline(prompt)?;
+        match line.strip_suffix('\\') {
+            Some(line) => {
+                message.push_str(line);
+                message.push('\n');
+                prompt = "... ";
+            }
+            None => {
+                message.push_str(&line);
+                return Ok(message);
+            }
+        }
+    }
+}
+
+/// Runs a slash command. Returns false when the user wants to leave.
+fn run_command(agent: &mut Agent, command: &str) -> anyhow::Result<bool> {
+    let (name, argument) = command
+        .split_once(char::is_whitespace)
+        .map(|(name, argument)| (name, argument.trim()))
+        .unwrap_or((command, ""));
+    match name {
+        "exit" | "quit" => return Ok(false),
+        "help" => println!("{}", HELP),
+        "model" => {
+            if !argument.is_empty() {
+                agent.set_model(argument);
+            }
+            println!("Model: {}", agent.args.model);
+        }
+        "safe" => {
+            match argument {
+                "" => {}
+                "on" => agent.args.safe = true,
+                "off" => agent.args.safe = false,
+                _ => anyhow::bail!("Usage: /safe [on|off]"),
+            }
+            println!("Safe mode: {}", if agent.args.safe { "on" } else { "off" });
+        }
+        "save" => {
+            if !argument.is_empty() && argument != agent.session_name {
+                if session::exists(argument)? {
+                    anyhow::bail!("A session named `{}` already exists", argument);
+                }
+                agent.session_name = argument.to_string();
+            }
+            agent.save()?;
+            println!("Saved as {}", agent.session_name);
+        }
+        "undo" => {
+            let Some(last_user) = agent
+                .state
+                .messages
+                .iter()
+                .rposition(crate::message::is_prompt)
+            else {
+                anyhow::bail!("Nothing to undo");
+            };
+            let removed = agent.state.messages.len() - last_user;
+            agent.state.messages.truncate(last_user);
+            agent.save()?;
+            println!("Removed {} messages", removed);
+        }
+        "reset" => {
+            agent.reset()?;
+            println!("Started session {}", agent.session_name);
+        }
+        _ => anyhow::bail!("Unknown command /{}, see /help", name),
+    }
+    Ok(true)
+}
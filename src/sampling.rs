@@ -0,0 +1,100 @@
+//! Sampling parameters sent with every completion request.
+
+use async_openai::types::{CreateChatCompletionRequest, Stop};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What `--temperature`, `--seed` and friends set. Anything left unset is up to the server.
+#[derive(clap::Args, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Sampling {
+    /// Randomness of the responses, from 0 to 2
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Only sample from the tokens making up this probability mass, from 0 to 1
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// Maximum number of tokens in each response
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// Text that ends a response when generated, may be given up to 4 times
+    #[arg(long = "stop", value_name = "TEXT")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// Seed for reproducible responses, on servers that support it
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// Penalty for repeating tokens by how often they already appear, from -2 to 2
+    #[arg(long, allow_hyphen_values = true)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    /// Penalty for repeating tokens that already appear at all, from -2 to 2
+    #[arg(long, allow_hyphen_values = true)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+}
+
+impl Sampling {
+    /// Takes the parameters that aren't set here from `other`.
+    pub fn or(self, other: Sampling) -> Sampling {
+        Sampling {
+            temperature: self.temperature.or(other.temperature),
+            top_p: self.top_p.or(other.top_p),
+            max_tokens: self.max_tokens.or(other.max_tokens),
+            stop: if self.stop.is_empty() {
+                other.stop
+            } else {
+                self.stop
+            },
+            seed: self.seed.or(other.seed),
+            frequency_penalty: self.frequency_penalty.or(other.frequency_penalty),
+            presence_penalty: self.presence_penalty.or(other.presence_penalty),
+        }
+    }
+
+    pub fn apply(&self, request: &mut CreateChatCompletionRequest) {
+        request.temperature = self.temperature;
+        request.top_p = self.top_p;
+        // Local servers don't all know `max_completion_tokens` yet.
+        #[allow(deprecated)]
+        {
+            request.max_tokens = self.max_tokens;
+        }
+        request.stop = (!self.stop.is_empty()).then(|| Stop::StringArray(self.stop.clone()));
+        request.seed = self.seed;
+        request.frequency_penalty = self.frequency_penalty;
+        request.presence_penalty = self.presence_penalty;
+    }
+}
+
+/// The parameters that are set, like `temperature=0.2 seed=42`.
+impl fmt::Display for Sampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(temperature) = self.temperature {
+            parts.push(format!("temperature={}", temperature));
+        }
+        if let Some(top_p) = self.top_p {
+            parts.push(format!("top_p={}", top_p));
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            parts.push(format!("max_tokens={}", max_tokens));
+        }
+        for stop in &self.stop {
+            parts.push(format!("stop={:?}", stop));
+        }
+        if let Some(seed) = self.seed {
+            parts.push(format!("seed={}", seed));
+        }
+        if let Some(penalty) = self.frequency_penalty {
+            parts.push(format!("frequency_penalty={}", penalty));
+        }
+        if let Some(penalty) = self.presence_penalty {
+            parts.push(format!("presence_penalty={}", penalty));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
@@ -0,0 +1,222 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Image used by the container sandbox when none is configured.
+pub const DEFAULT_IMAGE: &str = "docker.io/library/debian:stable-slim";
+/// Where the scratch directory is mounted inside a container.
+const CONTAINER_WORKDIR: &str = "/work";
+
+/// Where scripts run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sandbox {
+    /// Directly on this machine as the current user
+    Host,
+    /// In a bubblewrap sandbox with a read-only root and a writable scratch directory
+    Bwrap,
+    /// In a podman or docker container with the scratch directory mounted at /work
+    Container,
+}
+
+/// How the shell session is started, see [`Sandbox`].
+#[derive(Debug, Clone)]
+pub enum Backend {
+    Host,
+    Bwrap {
+        scratch: PathBuf,
+    },
+    Container {
+        runtime: String,
+        image: String,
+        scratch: PathBuf,
+    },
+    /// A shell on another machine, from `--target`.
+    Ssh {
+        target: String,
+    },
+}
+
+impl Backend {
+    /// Sets up the backend, creating the scratch directory if it needs one.
+    ///
+    /// A `target` runs everything over SSH, which can't be combined with a sandbox.
+    pub fn new(
+        sandbox: Sandbox,
+        image: &str,
+        scratch: Option<&Path>,
+        target: Option<&str>,
+        session_name: &str,
+    ) -> anyhow::Result<Self> {
+        if let Some(target) = target {
+            if sandbox != Sandbox::Host {
+                anyhow::bail!("--sandbox can't be combined with --target");
+            }
+            find_program("ssh").ok_or(anyhow::anyhow!("ssh is not installed"))?;
+            return Ok(Backend::Ssh {
+                target: target.to_string(),
+            });
+        }
+        Ok(match sandbox {
+            Sandbox::Host => Backend::Host,
+            Sandbox::Bwrap => {
+                find_program("bwrap").ok_or(anyhow::anyhow!("bwrap is not installed"))?;
+                Backend::Bwrap {
+                    scratch: scratch_dir(scratch, session_name)?,
+                }
+            }
+            Sandbox::Container => {
+                let runtime = ["podman", "docker"]
+                    .into_iter()
+                    .find(|runtime| find_program(runtime).is_some())
+                    .ok_or(anyhow::anyhow!("Neither podman nor docker is installed"))?;
+                Backend::Container {
+                    runtime: runtime.to_string(),
+                    image: image.to_string(),
+                    scratch: scratch_dir(scratch, session_name)?,
+                }
+            }
+        })
+    }
+
+    /// The command that starts `sh` in this environment. `name` identifies the session, it
+    /// names the container.
+    pub fn shell_command(&self, name: &str) -> anyhow::Result<Command> {
+        let command = match self {
+            Backend::Host => {
+                let mut command = Command::new("sh");
+                command.current_dir(std::env::current_dir()?);
+                command
+            }
+            Backend::Bwrap { scratch } => {
+                let mut command = Command::new("bwrap");
+                command
+                    .args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"])
+                    .args(["--tmpfs", "/tmp"])
+                    .arg("--bind")
+                    .args([scratch, scratch])
+                    .arg("--chdir")
+                    .arg(scratch)
+                    .args(["--unshare-all", "--share-net", "--die-with-parent"])
+                    .arg("sh");
+                command
+            }
+            Backend::Container {
+                runtime,
+                image,
+                scratch,
+            } => {
+                let mut command = Command::new(runtime);
+                command
+                    .args(["run", "--rm", "-i", "--name", name])
+                    .arg("-v")
+                    .arg(format!("{}:{}", scratch.display(), CONTAINER_WORKDIR))
+                    .args(["-w", CONTAINER_WORKDIR, image, "sh"]);
+                command
+            }
+            Backend::Ssh { target } => {
+                let mut command = ssh();
+                command.arg("-T").arg(target).arg("sh");
+                command
+            }
+        };
+        Ok(command)
+    }
+
+    /// The SSH destination, if scripts run on another machine.
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            Backend::Ssh { target } => Some(target),
+            _ => None,
+        }
+    }
+
+    /// Whether scripts run on another machine.
+    pub fn is_remote(&self) -> bool {
+        matches!(self, Backend::Ssh { .. })
+    }
+
+    /// Stops whatever keeps running after the shell process is killed.
+    ///
+    /// Killing `docker run` only detaches from the container, it has to be killed by name.
+    /// Killing `ssh` leaves the remote commands running, so their process `group` is killed
+    /// over a new connection.
+    pub fn stop(&self, name: &str, group: Option<&str>) {
+        let mut command = match (self, group) {
+            (Backend::Container { runtime, .. }, _) => {
+                let mut command = Command::new(runtime);
+                command.args(["kill", name]);
+                command
+            }
+            (Backend::Ssh { target }, Some(group)) => {
+                let mut command = ssh();
+                command.arg(target).arg(format!("kill -9 -{}", group));
+                command
+            }
+            _ => return,
+        };
+        let _ = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+
+    /// Tells the model where its commands run.
+    pub fn describe(&self) -> String {
+        match self {
+            Backend::Host => "Commands run directly on the user's machine.".to_string(),
+            Backend::Bwrap { scratch } => format!(
+                "Commands run in a sandbox: the filesystem is read-only except for {} (the starting directory) and /tmp, which is emptied when the session restarts. Network access is available.",
+                scratch.display()
+            ),
+            Backend::Container { runtime, image, .. } => format!(
+                "Commands run in a {} container from the {} image. Files in {} are kept, everything else is lost when the container restarts.",
+                runtime, image, CONTAINER_WORKDIR
+            ),
+            Backend::Ssh { target } => format!(
+                "Commands run on {} over SSH, not on the user's machine.",
+                target
+            ),
+        }
+    }
+}
+
+/// An `ssh` command sharing one master connection per destination, so restarting the shell
+/// or killing a script doesn't log in again.
+fn ssh() -> Command {
+    let control_path = std::env::temp_dir().join("ai_cli_ssh_%C");
+    let mut command = Command::new("ssh");
+    command
+        .args(["-o", "ControlMaster=auto", "-o", "ControlPersist=600"])
+        .arg("-o")
+        .arg(format!("ControlPath={}", control_path.display()));
+    command
+}
+
+/// Creates the writable directory of a sandbox and returns its absolute path.
+///
+/// Without an explicit directory each session gets its own under the data directory, so its
+/// files are still there when the conversation is continued.
+fn scratch_dir(scratch: Option<&Path>, session_name: &str) -> anyhow::Result<PathBuf> {
+    let scratch = match scratch {
+        Some(scratch) => scratch.to_path_buf(),
+        None => dirs::data_dir()
+            .ok_or(anyhow::anyhow!("No data directory for the sandbox"))?
+            .join("ai_cli")
+            .join("scratch")
+            .join(session_name),
+    };
+    fs::create_dir_all(&scratch)?;
+    // Bind mounts need an absolute path.
+    Ok(fs::canonicalize(scratch)?)
+}
+
+/// Looks a program up in `PATH`.
+fn find_program(name: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
@@ -0,0 +1,267 @@
+use async_openai::types::ChatCompletionRequestMessage;
+use chrono::{DateTime, Local, Utc};
+use clap::{Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::message;
+use crate::sampling::Sampling;
+use crate::sandbox::Sandbox;
+use crate::shell::ShellState;
+
+/// Longest title taken from the first message of a session.
+const TITLE_LENGTH: usize = 60;
+
+/// A saved conversation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct State {
+    pub messages: Vec<ChatCompletionRequestMessage>,
+    /// Shell session to restore when continuing
+    #[serde(default)]
+    pub shell: Option<ShellState>,
+    #[serde(default = "Utc::now")]
+    pub created: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    pub updated: DateTime<Utc>,
+    /// Model used for the last turn
+    #[serde(default)]
+    pub model: String,
+    /// API base the last turn was sent to
+    #[serde(default)]
+    pub api_base: String,
+    /// Sampling parameters of the last turn, reused when continuing
+    #[serde(default)]
+    pub sampling: Sampling,
+    /// Directory ai_cli was started from
+    #[serde(default)]
+    pub cwd: PathBuf,
+    #[serde(default)]
+    pub title: String,
+    /// SSH destination the scripts ran on, `None` for the local machine
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Inventory groups the scripts ran on, see `--hosts`
+    #[serde(default)]
+    pub hosts: Option<String>,
+    /// Where the scripts ran, `None` for sessions saved before it was recorded
+    #[serde(default)]
+    pub sandbox: Option<Sandbox>,
+    /// Image of the container sandbox
+    #[serde(default)]
+    pub sandbox_image: Option<String>,
+    /// Shell sessions of each host when running on several
+    #[serde(default)]
+    pub host_shells: BTreeMap<String, ShellState>,
+    /// Messages the user has sent, numbering the turns in the event log
+    #[serde(default)]
+    pub turns: usize,
+}
+
+impl State {
+    pub fn new(system_prompt: &str, cwd: PathBuf) -> Self {
+        let now = Utc::now();
+        State {
+            messages: vec![ChatCompletionRequestMessage::System(system_prompt.into())],
+            shell: None,
+            created: now,
+            updated: now,
+            model: String::new(),
+            api_base: String::new(),
+            sampling: Sampling::default(),
+            cwd,
+            title: String::new(),
+            target: None,
+            hosts: None,
+            sandbox: None,
+            sandbox_image: None,
+            host_shells: BTreeMap::new(),
+            turns: 0,
+        }
+    }
+
+    /// Sets the title from the first message if there isn't one yet.
+    pub fn set_title(&mut self, message: &str) {
+        if self.title.is_empty() {
+            let line = message.lines().next().unwrap_or_default();
+            self.title = line.chars().take(TITLE_LENGTH).collect();
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SessionsCommand {
+    /// List saved sessions, most recent first
+    List,
+    /// Print the messages of a session
+    Show { name: String },
+    /// Delete a session
+    Delete { name: String },
+    /// Rename a session
+    Rename { from: String, to: String },
+}
+
+/// Directory holding one JSON file per session, usually `~/.local/share/ai_cli/sessions`.
+fn sessions_dir() -> anyhow::Result<PathBuf> {
+    let dir = dirs::data_dir()
+        .ok_or(anyhow::anyhow!("No data directory on this system"))?
+        .join("ai_cli")
+        .join("sessions");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn session_path(name: &str) -> anyhow::Result<PathBuf> {
+    session_file(name, "json")
+}
+
+/// Messages replaced by summaries, one JSON array per line.
+fn archive_path(name: &str) -> anyhow::Result<PathBuf> {
+    session_file(name, "archive.jsonl")
+}
+
+fn session_file(name: &str, extension: &str) -> anyhow::Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        anyhow::bail!("Invalid session name `{}`", name);
+    }
+    Ok(sessions_dir()?.join(format!("{}.{}", name, extension)))
+}
+
+/// A fresh session name based on the current time.
+pub fn new_name() -> String {
+    format!(
+        "{}-{:04x}",
+        Local::now().format("%Y%m%d-%H%M%S"),
+        rand::random::<u16>()
+    )
+}
+
+pub fn exists(name: &str) -> anyhow::Result<bool> {
+    Ok(session_path(name)?.exists())
+}
+
+pub fn load(name: &str) -> anyhow::Result<State> {
+    let path = session_path(name)?;
+    let s = fs::read_to_string(&path)
+        .map_err(|e| anyhow::anyhow!("Can't read session `{}`: {}", name, e))?;
+    Ok(serde_json::from_str(&s)?)
+}
+
+pub fn save(name: &str, state: &mut State) -> anyhow::Result<()> {
+    state.updated = Utc::now();
+    let json = serde_json::to_string(state)?;
+    fs::write(session_path(name)?, json)?;
+    Ok(())
+}
+
+/// Appends messages that are about to be removed from the session to its archive.
+pub fn archive(name: &str, messages: &[ChatCompletionRequestMessage]) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(archive_path(name)?)?;
+    writeln!(file, "{}", serde_json::to_string(messages)?)?;
+    Ok(())
+}
+
+/// All sessions, most recently updated first. Unreadable files are skipped.
+pub fn list() -> anyhow::Result<Vec<(String, State)>> {
+    let mut sessions = Vec::new();
+    for entry in fs::read_dir(sessions_dir()?)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if let Ok(state) = load(name) {
+            sessions.push((name.to_string(), state));
+        }
+    }
+    sessions.sort_by_key(|(_, state)| std::cmp::Reverse(state.updated));
+    Ok(sessions)
+}
+
+/// The most recently updated session started from `cwd`.
+pub fn latest_for(cwd: &Path) -> anyhow::Result<Option<String>> {
+    Ok(list()?
+        .into_iter()
+        .find(|(_, state)| state.cwd == cwd)
+        .map(|(name, _)| name))
+}
+
+/// Runs one of the `sessions` subcommands.
+pub fn run(command: SessionsCommand) -> anyhow::Result<()> {
+    match command {
+        SessionsCommand::List => {
+            for (name, state) in list()? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    name,
+                    state.updated.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+                    state.model,
+                    state.cwd.display(),
+                    state.title
+                );
+            }
+        }
+        SessionsCommand::Show { name } => {
+            let state = load(&name)?;
+            println!("Title: {}", state.title);
+            println!("Model: {}", state.model);
+            if !state.api_base.is_empty() {
+                println!("API base: {}", state.api_base);
+            }
+            if state.sampling != Sampling::default() {
+                println!("Sampling: {}", state.sampling);
+            }
+            println!("Directory: {}", state.cwd.display());
+            if let Some(target) = &state.target {
+                println!("Target: {}", target);
+            }
+            if let Some(hosts) = &state.hosts {
+                println!("Hosts: {}", hosts);
+            }
+            if let Some(sandbox) = state.sandbox.and_then(|s| s.to_possible_value()) {
+                match &state.sandbox_image {
+                    Some(image) => println!("Sandbox: {} ({})", sandbox.get_name(), image),
+                    None => println!("Sandbox: {}", sandbox.get_name()),
+                }
+            }
+            println!(
+                "Created: {}",
+                state.created.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+            );
+            for message in &state.messages {
+                println!("\n[{}]\n{}", message::role(message), message::text(message));
+            }
+        }
+        SessionsCommand::Delete { name } => {
+            if !exists(&name)? {
+                anyhow::bail!("No session named `{}`", name);
+            }
+            fs::remove_file(session_path(&name)?)?;
+            let archive = archive_path(&name)?;
+            if archive.exists() {
+                fs::remove_file(archive)?;
+            }
+        }
+        SessionsCommand::Rename { from, to } => {
+            if !exists(&from)? {
+                anyhow::bail!("No session named `{}`", from);
+            }
+            if exists(&to)? {
+                anyhow::bail!("A session named `{}` already exists", to);
+            }
+            fs::rename(session_path(&from)?, session_path(&to)?)?;
+            let archive = archive_path(&from)?;
+            if archive.exists() {
+                fs::rename(archive, archive_path(&to)?)?;
+            }
+        }
+    }
+    Ok(())
+}
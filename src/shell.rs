@@ -0,0 +1,676 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+
+use crate::output;
+use crate::sandbox::Backend;
+use crate::text;
+use std::time::{Duration, Instant};
+
+/// Follows the status in the end marker when the shell itself exited, see [`Shell::spawn`].
+const EXITED: &str = ":exited";
+/// Variables the shell manages itself, restoring them would only cause confusion.
+const VOLATILE_VARS: &[&str] = &["PWD", "OLDPWD", "SHLVL", "_"];
+
+/// Working directory and environment of a shell session, saved with the conversation.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShellState {
+    pub cwd: PathBuf,
+    /// Variables that differ from the environment ai_cli was started with.
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Output of one command run through the shell.
+struct Finished {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    /// `None` if the shell exited before the command finished.
+    status: Option<i32>,
+    /// The command ended the shell, with `exit` or under `set -e`.
+    exited: bool,
+    /// Why the command was killed, if it was.
+    killed: Option<String>,
+}
+
+/// How a command sent through [`Shell::roundtrip`] is supervised.
+#[derive(Default)]
+struct Watch<'a> {
+    /// Echo output to the terminal as it arrives.
+    live: bool,
+    deadline: Option<(Instant, Duration)>,
+    stop: Option<&'a dyn Fn() -> bool>,
+}
+
+/// Result of a script run through the shell.
+#[derive(Debug, Clone)]
+pub struct ScriptOutput {
+    /// Raw output, it is decoded when formatted for the model.
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Exit code, `None` if the shell died before the script finished.
+    pub status: Option<i32>,
+    pub elapsed: Duration,
+    /// Why the session had to be restarted, if it was.
+    pub restarted: Option<String>,
+    /// Stopped with Ctrl-C while it had the terminal, which ai_cli itself never sees.
+    pub interrupted: bool,
+}
+
+impl ScriptOutput {
+    /// One line status for the terminal and the end of the model message.
+    pub fn summary(&self) -> String {
+        let elapsed = self.elapsed.as_secs_f64();
+        match self.status {
+            // The shell reports death by a signal as 128 plus its number.
+            Some(code) if code > 128 && code - 128 <= 64 => format!(
+                "Exit code: {} (killed by {}), elapsed: {:.1}s",
+                code,
+                signal_name(code - 128),
+                elapsed
+            ),
+            Some(code) => format!("Exit code: {}, elapsed: {:.1}s", code, elapsed),
+            None => format!("No exit code, elapsed: {:.1}s", elapsed),
+        }
+    }
+
+    /// Formats the output for the model, keeping at most `limit` bytes of each stream (0 for no
+    /// limit).
+    ///
+    /// Stderr is labeled separately when there is any. Binary output is described instead of
+    /// included.
+    pub fn to_message(&self, limit: usize) -> String {
+        let mut message = String::new();
+        if let Some(reason) = &self.restarted {
+            message.push_str(reason);
+            message.push('\n');
+        }
+        let stdout = decode(&self.stdout, limit);
+        if self.stderr.is_empty() {
+            message.push_str(&stdout);
+        } else {
+            if !self.stdout.is_empty() {
+                message.push_str(&format!("stdout:\n{}", stdout));
+                if !message.ends_with('\n') {
+                    message.push('\n');
+                }
+            }
+            message.push_str(&format!("stderr:\n{}", decode(&self.stderr, limit)));
+        }
+        if !message.is_empty() && !message.ends_with('\n') {
+            message.push('\n');
+        }
+        message.push_str(&self.summary());
+        message
+    }
+}
+
+/// Turns output into text for the model, keeping the start and the end if it's over `limit`.
+fn decode(bytes: &[u8], limit: usize) -> String {
+    if text::is_binary(bytes) {
+        return format!(
+            "[binary output, {} bytes, not shown; pipe it through a tool like `xxd | head` or `file -` to inspect it]\n",
+            bytes.len()
+        );
+    }
+    text::shorten(&String::from_utf8_lossy(bytes), limit)
+}
+
+/// Name of a signal number, for the usual ones.
+fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        6 => "SIGABRT",
+        7 => "SIGBUS",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        _ => return format!("signal {}", signal),
+    };
+    name.to_string()
+}
+
+/// A long-lived `sh` process fed over pipes.
+///
+/// Scripts are sourced into the same process so `cd`, exported variables and activated virtualenvs
+/// carry over to the next call. The end of each script is detected with a random marker printed
+/// on both stdout and stderr.
+///
+/// On unix the shell gets its own process group so a timeout can kill everything a script
+/// started. While a script runs that group is made the terminal's foreground group, which keeps
+/// Ctrl-C and password prompts working.
+pub struct Shell {
+    child: Child,
+    stdin: ChildStdin,
+    output: Receiver<(Stream, Vec<u8>)>,
+    marker: String,
+    /// Names the container, if the backend runs one.
+    name: String,
+    backend: Backend,
+    /// Process group of a remote shell, for killing what it started.
+    remote_group: Option<String>,
+    /// Printed in front of every line of live output, see [`Shell::set_label`].
+    label: Option<String>,
+    state: ShellState,
+}
+
+impl Shell {
+    /// Starts a new shell, restoring the working directory and environment from `restore`.
+    pub fn spawn(backend: &Backend, restore: Option<&ShellState>) -> anyhow::Result<Self> {
+        let id = rand::random::<u64>();
+        let name = format!("ai_cli_{:016x}", id);
+        let mut command = backend.shell_command(&name)?;
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command.spawn()?;
+
+        let (sender, output) = mpsc::channel();
+        forward(child.stdout.take(), Stream::Stdout, sender.clone());
+        forward(child.stderr.take(), Stream::Stderr, sender);
+
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or(anyhow::anyhow!("Shell has no stdin"))?;
+        let mut shell = Shell {
+            child,
+            stdin,
+            output,
+            marker: format!("__{}__", name),
+            name,
+            backend: backend.clone(),
+            remote_group: None,
+            label: None,
+            state: ShellState::default(),
+        };
+        // ssh may need to ask for a password.
+        let terminal = Terminal::hand_to(shell.child.id());
+
+        // A script calling `exit` ends the whole session, this still reports its status.
+        let trap = format!(
+            "trap '__ai_cli_status=$?; rm -f \"${{__ai_cli_script:-}}\"; printf \"%s%s{exited}\\n\" {marker} \"$__ai_cli_status\"; printf \"%s\\n\" {marker} >&2' EXIT",
+            exited = EXITED,
+            marker = shell.marker
+        );
+        shell.roundtrip(&trap, Watch::default())?;
+
+        if backend.is_remote() {
+            let finished =
+                shell.roundtrip("ps -o pgid= -p $$ 2>/dev/null || echo $$", Watch::default())?;
+            if finished.status.is_none() {
+                anyhow::bail!(
+                    "Could not start the remote shell: {}",
+                    String::from_utf8_lossy(&finished.stderr).trim()
+                );
+            }
+            shell.remote_group = Some(String::from_utf8_lossy(&finished.stdout).trim().to_string());
+        }
+
+        if let Some(restore) = restore {
+            let mut commands = String::new();
+            for (name, value) in &restore.env {
+                if is_valid_name(name) {
+                    commands.push_str(&format!("export {}={}\n", name, quote(value)));
+                }
+            }
+            commands.push_str(&format!(
+                "cd {} 2>/dev/null\n",
+                quote(&restore.cwd.to_string_lossy())
+            ));
+            shell.roundtrip(&commands, Watch::default())?;
+        }
+        shell.state = shell.capture()?;
+        drop(terminal);
+        Ok(shell)
+    }
+
+    /// The working directory and environment after the last command.
+    pub fn state(&self) -> &ShellState {
+        &self.state
+    }
+
+    /// Runs a script in the session, echoing its output live.
+    ///
+    /// The script is killed once `timeout` passes or `stop` returns true.
+    pub fn run(
+        &mut self,
+        script: &str,
+        timeout: Option<Duration>,
+        stop: &dyn Fn() -> bool,
+    ) -> anyhow::Result<ScriptOutput> {
+        // The shell writes the file itself, so this works the same inside a sandbox. It is
+        // sourced with stdin detached so the script can't eat the commands that follow it.
+        // Options like `set -e` are reset afterwards, they would break the scripts that follow.
+        let command = format!(
+            "__ai_cli_script=\"${{TMPDIR:-/tmp}}/{name}.sh\"\n\
+             cat > \"$__ai_cli_script\" <<'{marker}'\n{script}\n{marker}\n\
+             __ai_cli_options=$-\n\
+             {{ . \"$__ai_cli_script\"; }} </dev/null; __ai_cli_status=$?\n\
+             set +eux; case $__ai_cli_options in *e*) set -e;; esac\n\
+             case $__ai_cli_options in *u*) set -u;; esac\n\
+             case $__ai_cli_options in *x*) set -x;; esac\n\
+             rm -f \"$__ai_cli_script\"; (exit $__ai_cli_status)",
+            name = self.name,
+            marker = self.marker,
+            script = script,
+        );
+        let started = Instant::now();
+        let terminal = self
+            .label
+            .is_none()
+            .then(|| Terminal::hand_to(self.child.id()));
+        let finished = self.roundtrip(
+            &command,
+            Watch {
+                live: true,
+                deadline: timeout.map(|timeout| (started + timeout, timeout)),
+                stop: Some(stop),
+            },
+        );
+        let held = terminal.as_ref().is_some_and(Terminal::is_held);
+        drop(terminal);
+        let finished = finished?;
+        let elapsed = started.elapsed();
+        // Ctrl-C went to the script's process group. The shell reports a script killed by it as
+        // 130, or dies along with it.
+        let interrupted = held
+            && match finished.status {
+                Some(status) => status == 130,
+                None => finished.killed.is_none() && self.died_of_interrupt(),
+            };
+
+        let restarted = if finished.status.is_none() || finished.exited {
+            let label = self.label.take();
+            *self = Shell::spawn(&self.backend, Some(&self.state))?;
+            self.label = label;
+            let reason = match finished.killed {
+                Some(reason) => reason,
+                None if finished.exited => "The script exited the shell".to_string(),
+                None => "The shell exited while running the script".to_string(),
+            };
+            Some(format!(
+                "{}, a new session was started with the previous directory and environment.",
+                reason
+            ))
+        } else {
+            self.state = self.capture()?;
+            None
+        };
+
+        Ok(ScriptOutput {
+            stdout: finished.stdout,
+            stderr: finished.stderr,
+            status: finished.status,
+            elapsed,
+            restarted,
+            interrupted,
+        })
+    }
+
+    /// Marks the output of this shell with `label`, for running several shells at once.
+    ///
+    /// A labeled shell never takes over the terminal, only one of them could have it.
+    pub fn set_label(&mut self, label: String) {
+        self.label = Some(label);
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Name of the machine the shell runs on.
+    pub fn hostname(&mut self) -> anyhow::Result<String> {
+        let finished = self.roundtrip("uname -n", Watch::default())?;
+        Ok(String::from_utf8_lossy(&finished.stdout).trim().to_string())
+    }
+
+    /// Reads the current directory and environment out of the shell.
+    fn capture(&mut self) -> anyhow::Result<ShellState> {
+        let command =
+            "pwd; awk 'BEGIN { for (k in ENVIRON) printf \"%s=%s%c\", k, ENVIRON[k], 0 }'";
+        let finished = self.roundtrip(command, Watch::default())?;
+        if finished.status.is_none() {
+            // A sandbox that failed to start says why on stderr.
+            anyhow::bail!(
+                "Shell exited while reading its state: {}",
+                String::from_utf8_lossy(&finished.stderr).trim()
+            );
+        }
+        let stdout = String::from_utf8_lossy(&finished.stdout);
+        let (cwd, vars) = stdout.split_once('\n').unwrap_or((&stdout, ""));
+
+        let mut env = BTreeMap::new();
+        for var in vars.split('\0') {
+            let Some((name, value)) = var.split_once('=') else {
+                continue;
+            };
+            if VOLATILE_VARS.contains(&name) {
+                continue;
+            }
+            if std::env::var(name).ok().as_deref() != Some(value) {
+                env.insert(name.to_string(), value.to_string());
+            }
+        }
+        Ok(ShellState {
+            cwd: PathBuf::from(cwd),
+            env,
+        })
+    }
+
+    /// Sends a command followed by the end markers and collects its output.
+    fn roundtrip(&mut self, command: &str, watch: Watch) -> anyhow::Result<Finished> {
+        let input = format!(
+            "{}\n__ai_cli_status=$?; printf '%s%s\\n' '{marker}' \"$__ai_cli_status\"; printf '%s\\n' '{marker}' >&2\n",
+            command,
+            marker = self.marker
+        );
+        // A shell that already exited shows up as a closed output channel below.
+        match self.stdin.write_all(input.as_bytes()) {
+            Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut status = None;
+        let mut exited = false;
+        let mut stderr_done = false;
+        let mut killed: Option<(String, Instant)> = None;
+        while status.is_none() || !stderr_done {
+            // Checked on every line too, a script that keeps printing must still be stopped.
+            if let Some((_, at)) = &killed {
+                // Something that escaped the process group may still hold the pipes.
+                if at.elapsed() > Duration::from_secs(1) {
+                    break;
+                }
+            } else {
+                let reason = if let Some((deadline, timeout)) = watch.deadline
+                    && Instant::now() >= deadline
+                {
+                    Some(format!("Timed out after {}s", timeout.as_secs()))
+                } else if watch.stop.is_some_and(|stop| stop()) {
+                    Some("Interrupted by the user".to_string())
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    self.kill();
+                    killed = Some((reason, Instant::now()));
+                }
+            }
+            let (stream, line) = match self.output.recv_timeout(Duration::from_millis(100)) {
+                Ok(received) => received,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            let (data, end) = match find(&line, self.marker.as_bytes()) {
+                Some(pos) => (&line[..pos], Some(&line[pos + self.marker.len()..])),
+                None => (&line[..], None),
+            };
+            match stream {
+                Stream::Stdout => {
+                    // Stdout belongs to the JSON events then.
+                    if watch.live && output::is_json() {
+                        echo(&mut io::stderr(), self.label.as_deref(), data);
+                    } else if watch.live {
+                        echo(&mut io::stdout(), self.label.as_deref(), data);
+                    }
+                    stdout.extend_from_slice(data);
+                    if let Some(end) = end {
+                        let end = String::from_utf8_lossy(end);
+                        let end = end.trim();
+                        let code = match end.strip_suffix(EXITED) {
+                            Some(code) => {
+                                exited = true;
+                                code
+                            }
+                            None => end,
+                        };
+                        status = Some(code.parse().unwrap_or(-1));
+                    }
+                }
+                Stream::Stderr => {
+                    if watch.live {
+                        echo(&mut io::stderr(), self.label.as_deref(), data);
+                    }
+                    stderr.extend_from_slice(data);
+                    stderr_done |= end.is_some();
+                }
+            }
+        }
+        if killed.is_some() {
+            // Whatever the shell printed after the kill doesn't count as an exit code.
+            status = None;
+        }
+        Ok(Finished {
+            stdout,
+            stderr,
+            status,
+            exited,
+            killed: killed.map(|(reason, _)| reason),
+        })
+    }
+
+    /// Whether the shell, which has closed its output, was killed by SIGINT.
+    fn died_of_interrupt(&mut self) -> bool {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            self.child
+                .wait()
+                .is_ok_and(|status| status.signal() == Some(libc::SIGINT))
+        }
+        #[cfg(not(unix))]
+        false
+    }
+
+    /// Kills the shell along with everything it started.
+    fn kill(&mut self) {
+        #[cfg(unix)]
+        // SAFETY: plain syscall, the shell leads its own process group.
+        unsafe {
+            libc::kill(-(self.child.id() as libc::pid_t), libc::SIGKILL);
+        }
+        let _ = self.child.kill();
+        self.backend.stop(&self.name, self.remote_group.as_deref());
+    }
+}
+
+impl Drop for Shell {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        // Background jobs on a remote host outlive the session, like they do locally.
+        self.backend.stop(&self.name, None);
+    }
+}
+
+/// Forwards the lines of a child pipe to the channel from a background thread.
+fn forward(
+    pipe: Option<impl Read + Send + 'static>,
+    stream: Stream,
+    sender: Sender<(Stream, Vec<u8>)>,
+) {
+    let Some(pipe) = pipe else {
+        return;
+    };
+    thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        loop {
+            let mut line = Vec::new();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if sender.send((stream, line)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Prints live output, prefixed with the label of the shell if it has one.
+fn echo(out: &mut impl Write, label: Option<&str>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    let _ = match label {
+        Some(label) => out
+            .write_all(format!("[{}] ", label).as_bytes())
+            .and_then(|_| out.write_all(data)),
+        None => out.write_all(data),
+    }
+    .and_then(|_| out.flush());
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Single-quotes a string for the shell.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Gives the controlling terminal to the shell's process group until dropped.
+///
+/// Does nothing when stdin is not a terminal or on platforms without job control.
+struct Terminal {
+    #[cfg(unix)]
+    previous: Option<libc::pid_t>,
+}
+
+impl Terminal {
+    fn hand_to(process_group: u32) -> Self {
+        #[cfg(unix)]
+        // SAFETY: plain syscalls on stdin. SIGTTOU is ignored so that taking the terminal back
+        // from the background doesn't stop us.
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return Terminal { previous: None };
+            }
+            libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+            let previous = libc::tcgetpgrp(libc::STDIN_FILENO);
+            if previous < 0
+                || libc::tcsetpgrp(libc::STDIN_FILENO, process_group as libc::pid_t) != 0
+            {
+                return Terminal { previous: None };
+            }
+            Terminal {
+                previous: Some(previous),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = process_group;
+            Terminal {}
+        }
+    }
+
+    /// Whether the terminal was actually handed over.
+    fn is_held(&self) -> bool {
+        #[cfg(unix)]
+        {
+            self.previous.is_some()
+        }
+        #[cfg(not(unix))]
+        {
+            false
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(previous) = self.previous {
+            // SAFETY: see `hand_to`.
+            unsafe {
+                libc::tcsetpgrp(libc::STDIN_FILENO, previous);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Script, exit code, whether the session had to be restarted.
+    const CASES: &[(&str, i32, bool)] = &[
+        ("true", 0, false),
+        ("false", 1, false),
+        ("exit 0", 0, true),
+        ("exit 3", 3, true),
+        ("set -e; false; echo unreachable", 1, true),
+        ("set -eu", 0, false),
+        // Options set by the previous script are gone.
+        ("grep -q nothing /dev/null; echo $UNSET_VARIABLE", 0, false),
+        ("cd /tmp", 0, false),
+        ("exit 5", 5, true),
+        // The new session keeps the directory.
+        ("test \"$(pwd)\" = /tmp", 0, false),
+    ];
+
+    #[test]
+    fn reports_exit_codes() {
+        let mut shell = Shell::spawn(&Backend::Host, None).unwrap();
+        for (script, code, restarted) in CASES {
+            let output = shell.run(script, None, &|| false).unwrap();
+            assert_eq!(output.status, Some(*code), "status of {:?}", script);
+            assert_eq!(
+                output.restarted.is_some(),
+                *restarted,
+                "restart after {:?}",
+                script
+            );
+        }
+    }
+
+    #[test]
+    fn times_out_chatty_scripts() {
+        let mut shell = Shell::spawn(&Backend::Host, None).unwrap();
+        let started = Instant::now();
+        let output = shell
+            .run(
+                "while :; do echo tick; sleep 0.05; done",
+                Some(Duration::from_secs(1)),
+                &|| false,
+            )
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(output.status, None);
+        assert!(output.restarted.unwrap().starts_with("Timed out after 1s"));
+    }
+}
@@ -0,0 +1,100 @@
+use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionRequest};
+
+use crate::completion::Endpoints;
+use crate::session::{self, State};
+use crate::{message, text};
+
+/// Messages at the end of the conversation that are never summarized.
+const KEEP_RECENT: usize = 6;
+/// Longest text taken from a single message when building the transcript to summarize, in bytes.
+const MAX_MESSAGE_BYTES: usize = 4000;
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:\n";
+const SUMMARY_PROMPT: &str = r#"
+You summarize a conversation between a user and an AI assistant that runs terminal commands on the user's machine.
+Write a concise summary that lets the assistant continue the work without the original messages. Keep:
+- the user's goals and any requirements or preferences they stated
+- what has been done so far, including important commands, paths, package names, versions and configuration values
+- errors that were hit and how they were resolved
+- what is still left to do
+Reply with the summary only.
+"#;
+
+/// Replaces the older messages of the session with a summary written by the model.
+///
+/// The system prompt, the user's latest prompt and the last few messages are kept as they are.
+/// The replaced messages are archived next to the session first, so nothing is lost. Returns
+/// whether a summary was made.
+pub async fn compact(
+    endpoints: &Endpoints,
+    model: &str,
+    session_name: &str,
+    state: &mut State,
+) -> anyhow::Result<bool> {
+    let first = usize::from(matches!(
+        state.messages.first(),
+        Some(ChatCompletionRequestMessage::System(_))
+    ));
+    // What the user currently asks for stays in their own words.
+    let last_prompt = state
+        .messages
+        .iter()
+        .rposition(message::is_prompt)
+        .unwrap_or(state.messages.len());
+    // Tool results have to stay behind the assistant message that asked for them.
+    let mut end = state
+        .messages
+        .len()
+        .saturating_sub(KEEP_RECENT)
+        .min(last_prompt);
+    while end > first && matches!(state.messages[end], ChatCompletionRequestMessage::Tool(_)) {
+        end -= 1;
+    }
+    if end <= first + 1 {
+        return Ok(false);
+    }
+
+    let summary = summarize(endpoints, model, &state.messages[first..end]).await?;
+    session::archive(session_name, &state.messages[first..end])?;
+    state.messages.splice(
+        first..end,
+        [ChatCompletionRequestMessage::User(
+            format!("{}{}", SUMMARY_PREFIX, summary).into(),
+        )],
+    );
+    Ok(true)
+}
+
+/// Asks the model for a summary of the messages.
+async fn summarize(
+    endpoints: &Endpoints,
+    model: &str,
+    messages: &[ChatCompletionRequestMessage],
+) -> anyhow::Result<String> {
+    let transcript = messages
+        .iter()
+        .map(|m| {
+            format!(
+                "[{}]\n{}",
+                message::role(m),
+                text::shorten(&message::text(m), MAX_MESSAGE_BYTES)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let request = CreateChatCompletionRequest {
+        model: model.to_string(),
+        messages: vec![
+            ChatCompletionRequestMessage::System(SUMMARY_PROMPT.trim().into()),
+            ChatCompletionRequestMessage::User(transcript.into()),
+        ],
+        ..Default::default()
+    };
+    let response = endpoints.create(&request).await?;
+    response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .filter(|summary| !summary.trim().is_empty())
+        .ok_or(anyhow::anyhow!("The model returned an empty summary"))
+}
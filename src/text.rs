@@ -0,0 +1,57 @@
+//! Helpers for putting command output and files into messages.
+
+/// Bytes looked at to tell binary output from text.
+const SNIFF_LEN: usize = 8192;
+
+/// Whether data looks binary: it has NUL bytes, or much of it isn't printable text.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(SNIFF_LEN)];
+    if sample.is_empty() {
+        return false;
+    }
+    if sample.contains(&0) {
+        return true;
+    }
+    // A multi-byte character cut off at the end of the sample is not a sign of binary data.
+    let text = String::from_utf8_lossy(sample);
+    let odd = text
+        .chars()
+        .filter(|c| {
+            *c == char::REPLACEMENT_CHARACTER
+                || (c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x1b' | '\x08'))
+        })
+        .count();
+    odd * 10 > text.chars().count()
+}
+
+/// Keeps the start and the end of `text` if it's longer than `limit` bytes, 0 for no limit.
+pub fn shorten(text: &str, limit: usize) -> String {
+    if limit == 0 || text.len() <= limit {
+        return text.to_string();
+    }
+    // Cut at line ends when there are any, half a line is easy to misread.
+    let head = floor_char_boundary(text, limit / 2);
+    let head = text[..head].rfind('\n').map_or(head, |end| end + 1);
+    let tail = ceil_char_boundary(text, text.len() - limit / 2);
+    let tail = text[tail..].find('\n').map_or(tail, |end| tail + end + 1);
+    format!(
+        "{}[... {} bytes omitted ...]\n{}",
+        &text[..head],
+        tail - head,
+        &text[tail..]
+    )
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
@@ -0,0 +1,297 @@
+use async_openai::types::ChatCompletionRequestMessage;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::ops::Range;
+use tiktoken_rs::CoreBPE;
+
+use crate::message;
+
+/// Tokens spent on the role and separators of every message, per OpenAI's counting guide.
+const MESSAGE_OVERHEAD: usize = 4;
+/// Tokens spent priming the reply.
+const REPLY_OVERHEAD: usize = 3;
+/// Rough cost of an image or audio part, which can't be counted from text.
+const NON_TEXT_PART_TOKENS: usize = 85;
+/// Messages at the end of the conversation that are never trimmed.
+const KEEP_RECENT: usize = 4;
+/// Lines kept from each end of an old script output when it is shortened.
+const TRIMMED_LINES: usize = 5;
+
+/// How tokens are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tokenizer {
+    /// Pick the BPE from the model name, falling back to the heuristic for unknown models
+    Auto,
+    /// cl100k_base, used by GPT-4 and GPT-3.5
+    Cl100k,
+    /// o200k_base, used by GPT-4o and newer
+    O200k,
+    /// Whitespace separated words times 1.2
+    Heuristic,
+}
+
+pub struct TokenCounter {
+    bpe: Option<&'static CoreBPE>,
+}
+
+impl TokenCounter {
+    pub fn new(tokenizer: Tokenizer, model: &str) -> Self {
+        let bpe = match tokenizer {
+            Tokenizer::Auto => tiktoken_rs::bpe_for_model(model).ok(),
+            Tokenizer::Cl100k => Some(tiktoken_rs::cl100k_base_singleton()),
+            Tokenizer::O200k => Some(tiktoken_rs::o200k_base_singleton()),
+            Tokenizer::Heuristic => None,
+        };
+        TokenCounter { bpe }
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        match self.bpe {
+            Some(bpe) => bpe.encode_ordinary(text).len(),
+            None => (text.split_whitespace().count() as f64 * 1.2).round() as usize,
+        }
+    }
+
+    pub fn count_message(&self, message: &ChatCompletionRequestMessage) -> usize {
+        MESSAGE_OVERHEAD
+            + self.count(&message::text(message))
+            + message::non_text_parts(message) * NON_TEXT_PART_TOKENS
+    }
+
+    /// Size of a whole request.
+    pub fn count_messages(&self, messages: &[ChatCompletionRequestMessage]) -> usize {
+        REPLY_OVERHEAD
+            + messages
+                .iter()
+                .map(|message| self.count_message(message))
+                .sum::<usize>()
+    }
+}
+
+/// Shrinks the messages about to be sent until they fit in `budget` tokens.
+///
+/// The turns before the latest user message go first: their script outputs are cut down to
+/// their first and last lines, then they are dropped, oldest first. Only then are the outputs of
+/// the latest turn cut, except for the newest message, and finally its older messages dropped.
+/// The system prompt, the latest user message and the last few messages are always kept, so the
+/// result can still be over budget. Returns whether anything was changed.
+pub fn fit_to_budget(
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    counter: &TokenCounter,
+    budget: usize,
+) -> bool {
+    let mut total = counter.count_messages(messages);
+    if total <= budget {
+        return false;
+    }
+
+    let last_user = messages.iter().rposition(message::is_prompt);
+    let recent = messages.len().saturating_sub(KEEP_RECENT);
+    let has_system = matches!(
+        messages.first(),
+        Some(ChatCompletionRequestMessage::System(_))
+    );
+    let protected: Vec<bool> = (0..messages.len())
+        .map(|index| index >= recent || Some(index) == last_user || (index == 0 && has_system))
+        .collect();
+    let turn = last_user.unwrap_or(0);
+    // The newest output is what the model is about to react to, leave it whole.
+    let newest = messages.len().saturating_sub(1);
+    let mut keep = vec![true; messages.len()];
+
+    for (index, message) in messages[..turn].iter_mut().enumerate() {
+        if total > budget && !protected[index] {
+            total -= trim_output(message, counter);
+        }
+    }
+    drop_messages(
+        messages,
+        counter,
+        budget,
+        0..turn,
+        &protected,
+        &mut keep,
+        &mut total,
+    );
+    for (index, message) in messages[..newest].iter_mut().enumerate() {
+        if total > budget && keep[index] {
+            total -= trim_output(message, counter);
+        }
+    }
+    let end = messages.len();
+    drop_messages(
+        messages,
+        counter,
+        budget,
+        turn..end,
+        &protected,
+        &mut keep,
+        &mut total,
+    );
+
+    let mut keep = keep.into_iter();
+    messages.retain(|_| keep.next().unwrap_or(true));
+    true
+}
+
+/// Marks the unprotected messages in `range` as dropped, oldest first, until `total` is within
+/// `budget`.
+///
+/// Tool results can't be sent without the assistant message that asked for them, so a message
+/// and the tool results following it are kept or dropped together.
+fn drop_messages(
+    messages: &[ChatCompletionRequestMessage],
+    counter: &TokenCounter,
+    budget: usize,
+    range: Range<usize>,
+    protected: &[bool],
+    keep: &mut [bool],
+    total: &mut usize,
+) {
+    let mut start = range.start;
+    while start < range.end && *total > budget {
+        let mut end = start + 1;
+        while end < messages.len() && matches!(messages[end], ChatCompletionRequestMessage::Tool(_))
+        {
+            end += 1;
+        }
+        if keep[start] && !protected[start..end].contains(&true) {
+            for index in start..end {
+                keep[index] = false;
+                *total -= counter.count_message(&messages[index]);
+            }
+        }
+        start = end;
+    }
+}
+
+/// Cuts a long script output down to its first and last lines, returns the tokens saved.
+fn trim_output(message: &mut ChatCompletionRequestMessage, counter: &TokenCounter) -> usize {
+    if !message::is_script_output(message) {
+        return 0;
+    }
+    let text = message::text(message);
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() <= TRIMMED_LINES * 2 {
+        return 0;
+    }
+    let before = counter.count_message(message);
+    message::replace_text(
+        message,
+        format!(
+            "{}\n[... {} lines removed to fit the context budget ...]\n{}",
+            lines[..TRIMMED_LINES].join("\n"),
+            lines.len() - TRIMMED_LINES * 2,
+            lines[lines.len() - TRIMMED_LINES..].join("\n")
+        ),
+    );
+    before.saturating_sub(counter.count_message(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{
+        ChatCompletionMessageToolCall, ChatCompletionToolType, FunctionCall,
+    };
+
+    /// Conversation, budget, and what happens to each message: kept (`=`), its output
+    /// trimmed (`~`) or dropped (`-`). Short messages count 6 tokens, tool calls 9, tool results
+    /// 125 or 28 trimmed, terminal_call outputs 132 or 29, and the request 3 more.
+    const CASES: &[(&str, usize, &str)] = &[
+        ("suctauctct", 429, "=========="),
+        // Outputs of older turns are trimmed first.
+        ("suctauctct", 400, "===~======"),
+        ("suctauctct", 330, "=-=~======"),
+        // A tool result goes with the call that asked for it.
+        ("suctauctct", 300, "=---======"),
+        // Older turns are gone before the latest turn is trimmed, never its newest output.
+        ("suctauctct", 250, "=----==~=="),
+        ("suctauctct", 100, "=----==~=="),
+        ("suaucttctcta", 550, "=--========="),
+        ("suaucttctcta", 400, "=--==~~====="),
+        ("suaucttctcta", 200, "=--==~~=~=~="),
+        // Then the older messages of the latest turn are dropped, but not a call whose result
+        // is among the last messages.
+        ("suaucttctcta", 100, "=--=---=~=~="),
+        ("suaucttctcta", 10, "=--=---=~=~="),
+        ("uaoauao", 190, "-=~===="),
+        ("uaoauao", 50, "---===="),
+    ];
+
+    /// Builds a conversation from one letter per message: `s`ystem, `u`ser prompt, `a`ssistant
+    /// text, assistant `c`alling a tool, its `t`ool result, or `o`utput of a terminal_call block.
+    /// Every message holds `m` and its index as a word.
+    fn conversation(spec: &str) -> Vec<ChatCompletionRequestMessage> {
+        let output = |index: usize| format!("m{}\n{}", index, vec!["line"; 100].join("\n"));
+        spec.chars()
+            .enumerate()
+            .map(|(index, kind)| match kind {
+                's' => ChatCompletionRequestMessage::System(format!("m{} system", index).into()),
+                'u' => ChatCompletionRequestMessage::User(format!("m{} prompt", index).into()),
+                'a' => message::assistant(Some(format!("m{} answer", index)), None),
+                'c' => message::assistant(
+                    Some(format!("m{} call", index)),
+                    Some(vec![ChatCompletionMessageToolCall {
+                        id: format!("call{}", index),
+                        r#type: ChatCompletionToolType::Function,
+                        function: FunctionCall {
+                            name: "terminal".into(),
+                            arguments: "{}".into(),
+                        },
+                    }]),
+                ),
+                't' => message::tool("call", output(index)),
+                'o' => message::script_output(&[(format!("m{}", index), output(index))]),
+                _ => panic!("unknown message kind {}", kind),
+            })
+            .collect()
+    }
+
+    /// What happened to each message of a conversation with `count` messages.
+    fn outcome(messages: &[ChatCompletionRequestMessage], count: usize) -> String {
+        let mut outcome = vec!['-'; count];
+        for message in messages {
+            let text = message::text(message);
+            let index: usize = text
+                .split_whitespace()
+                .find_map(|word| word.strip_prefix('m')?.parse().ok())
+                .unwrap();
+            outcome[index] = if text.contains("lines removed") {
+                '~'
+            } else {
+                '='
+            };
+        }
+        outcome.into_iter().collect()
+    }
+
+    #[test]
+    fn fits_to_budget() {
+        let counter = TokenCounter::new(Tokenizer::Heuristic, "");
+        for (spec, budget, expected) in CASES {
+            let mut messages = conversation(spec);
+            let changed = fit_to_budget(&mut messages, &counter, *budget);
+            assert_eq!(
+                outcome(&messages, spec.len()),
+                *expected,
+                "{} in {} tokens",
+                spec,
+                budget
+            );
+            assert_eq!(changed, expected.contains(['-', '~']), "{} changed", spec);
+            for pair in messages.windows(2) {
+                if let ChatCompletionRequestMessage::Tool(_) = pair[1] {
+                    assert!(
+                        matches!(&pair[0], ChatCompletionRequestMessage::Tool(_))
+                            || matches!(&pair[0], ChatCompletionRequestMessage::Assistant(a) if a.tool_calls.is_some()),
+                        "{} in {} tokens leaves a tool result without its call",
+                        spec,
+                        budget
+                    );
+                }
+            }
+        }
+    }
+}